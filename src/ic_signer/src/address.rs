@@ -0,0 +1,229 @@
+use crate::crypto::{DoubleSha256, Hasher256};
+use crate::error::SignerError;
+use crate::types::{AddressType, BitcoinNetwork};
+use crate::utils::{hash_keccak256, hash_sha256, vec8_to_hexstr};
+use bech32::{ToBase32, Variant};
+use k256::{
+    elliptic_curve::{ff::PrimeField, sec1::ToEncodedPoint},
+    ProjectivePoint, PublicKey, Scalar,
+};
+use ripemd::{Digest, Ripemd160};
+
+const DEFAULT_COSMOS_HRP: &str = "cosmos";
+
+// `pubkey_bytes` is compressed or uncompressed SEC1
+pub fn derive_address(
+    pubkey_bytes: &[u8],
+    address_type: &AddressType,
+) -> Result<String, SignerError> {
+    let pubkey = PublicKey::from_sec1_bytes(pubkey_bytes)
+        .map_err(|_| SignerError::InvalidKey("Not a secp256k1 public key".to_string()))?;
+    let compressed = pubkey.to_encoded_point(true).as_bytes().to_vec();
+    match address_type {
+        AddressType::Ethereum => eth_address(pubkey_bytes),
+        AddressType::BitcoinP2PKH(network) => {
+            let version = match network {
+                BitcoinNetwork::Mainnet => 0x00,
+                BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => 0x6f,
+            };
+            Ok(base58check(
+                &[&[version], &hash160(&compressed)[..]].concat(),
+            ))
+        }
+        AddressType::BitcoinP2WPKH(network) => {
+            segwit_address(network.hrp(), 0, &hash160(&compressed))
+        }
+        AddressType::BitcoinP2TR(network) => {
+            segwit_address(network.hrp(), 1, &taproot_output_key(&compressed)?)
+        }
+        AddressType::Cosmos(hrp) => {
+            let hrp = hrp.as_deref().unwrap_or(DEFAULT_COSMOS_HRP);
+            bech32::encode(hrp, hash160(&compressed).to_base32(), Variant::Bech32)
+                .map_err(|e| SignerError::InvalidArgument(format!("Cosmos prefix: {}", e)))
+        }
+        AddressType::Tron => {
+            let uncompressed = pubkey.to_encoded_point(false);
+            let hash = hash_keccak256(&uncompressed.as_bytes()[1..]);
+            Ok(base58check(&[&[0x41], &hash[12..]].concat()))
+        }
+        AddressType::Eos => {
+            let checksum = ripemd160(&[&compressed[..], b"K1"].concat());
+            let key = [&compressed[..], &checksum[..4]].concat();
+            Ok(format!("PUB_K1_{}", bs58::encode(key).into_string()))
+        }
+        AddressType::EosLegacy => {
+            let checksum = ripemd160(&compressed);
+            let key = [&compressed[..], &checksum[..4]].concat();
+            Ok(format!("EOS{}", bs58::encode(key).into_string()))
+        }
+    }
+}
+
+// EIP-55 checksummed
+pub fn eth_address(pubkey_bytes: &[u8]) -> Result<String, SignerError> {
+    let pubkey = PublicKey::from_sec1_bytes(pubkey_bytes)
+        .map_err(|_| SignerError::InvalidKey("Not a secp256k1 public key".to_string()))?;
+    let point = pubkey.to_encoded_point(false);
+    let address = vec8_to_hexstr(&hash_keccak256(&point.as_bytes()[1..])[12..].to_vec());
+    let checksum = hash_keccak256(address.as_bytes());
+    let address: String = address
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (checksum[i / 2] >> (4 * (1 - i % 2))) & 0xf;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    Ok(format!("0x{}", address))
+}
+
+impl BitcoinNetwork {
+    fn hrp(&self) -> &'static str {
+        match self {
+            BitcoinNetwork::Mainnet => "bc",
+            BitcoinNetwork::Testnet => "tb",
+            BitcoinNetwork::Regtest => "bcrt",
+        }
+    }
+}
+
+fn ripemd160(data: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(data).to_vec()
+}
+
+fn hash160(data: &[u8]) -> Vec<u8> {
+    ripemd160(&hash_sha256(data))
+}
+
+fn base58check(data: &[u8]) -> String {
+    let checksum = DoubleSha256::hash(data);
+    bs58::encode([data, &checksum[..4]].concat()).into_string()
+}
+
+fn segwit_address(hrp: &str, version: u8, program: &[u8]) -> Result<String, SignerError> {
+    let variant = if version == 0 {
+        Variant::Bech32
+    } else {
+        Variant::Bech32m
+    };
+    let version = bech32::u5::try_from_u8(version)
+        .map_err(|e| SignerError::InvalidArgument(format!("witness version: {}", e)))?;
+    let data = [vec![version], program.to_base32()].concat();
+    bech32::encode(hrp, data, variant)
+        .map_err(|e| SignerError::InvalidArgument(format!("segwit address: {}", e)))
+}
+
+// BIP-86 key path only output: the internal key tweaked by its own tagged hash
+fn taproot_output_key(compressed: &[u8]) -> Result<Vec<u8>, SignerError> {
+    let x_only = &compressed[1..];
+    // BIP-340 takes the point with the even y for an x coordinate
+    let internal = PublicKey::from_sec1_bytes(&[&[0x02], x_only].concat())
+        .map_err(|_| SignerError::InvalidKey("Not a secp256k1 public key".to_string()))?;
+    let tag = hash_sha256(b"TapTweak");
+    let tweak = hash_sha256(&[&tag[..], &tag[..], x_only].concat());
+    let tweak: Option<Scalar> = Scalar::from_repr(*k256::FieldBytes::from_slice(&tweak)).into();
+    let tweak = tweak.ok_or_else(|| SignerError::Crypto("Invalid taproot tweak".to_string()))?;
+    let output = (internal.to_projective() + ProjectivePoint::GENERATOR * tweak).to_affine();
+    Ok(output.to_encoded_point(true).as_bytes()[1..].to_vec())
+}
+
+#[cfg(test)]
+fn sec1(hex_key: &str) -> Vec<u8> {
+    crate::utils::hexstr_to_vec(hex_key).unwrap()
+}
+
+#[test]
+fn test_eth_address() {
+    // The key of private key 1
+    let publickey = sec1("0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8");
+    assert_eq!(
+        eth_address(&publickey).unwrap(),
+        "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+    );
+    assert!(eth_address(&[4; 65]).is_err());
+}
+
+#[test]
+fn test_derive_address() {
+    // The key of private key 1
+    let publickey = sec1("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+    let cases = [
+        (
+            AddressType::Ethereum,
+            "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf",
+        ),
+        (
+            AddressType::BitcoinP2PKH(BitcoinNetwork::Mainnet),
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+        ),
+        (
+            AddressType::BitcoinP2PKH(BitcoinNetwork::Testnet),
+            "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r",
+        ),
+        (
+            AddressType::BitcoinP2WPKH(BitcoinNetwork::Mainnet),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+        ),
+        (
+            AddressType::BitcoinP2WPKH(BitcoinNetwork::Regtest),
+            "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+        ),
+        (
+            AddressType::Cosmos(None),
+            "cosmos1w508d6qejxtdg4y5r3zarvary0c5xw7k6ah60c",
+        ),
+        (
+            AddressType::Cosmos(Some("osmo".to_string())),
+            "osmo1w508d6qejxtdg4y5r3zarvary0c5xw7kjxy2e2",
+        ),
+        (AddressType::Tron, "TMVQGm1qAQYVdetCeGRRkTWYYrLXuHK2HC"),
+        (
+            AddressType::Eos,
+            "PUB_K1_5p78kHbL33Rn3JWkTWRE2B9uz6gy4r1KbfAKLNQGE3ovLY8E9M",
+        ),
+        (
+            AddressType::EosLegacy,
+            "EOS5p78kHbL33Rn3JWkTWRE2B9uz6gy4r1KbfAKLNQGE3ovMBS5bu",
+        ),
+    ];
+    for (address_type, expected) in cases {
+        assert_eq!(derive_address(&publickey, &address_type).unwrap(), expected);
+    }
+
+    // BIP-86 test vector, the first receiving address of account 0
+    let internal_key = sec1("02cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115");
+    assert_eq!(
+        derive_address(
+            &internal_key,
+            &AddressType::BitcoinP2TR(BitcoinNetwork::Mainnet)
+        )
+        .unwrap(),
+        "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+    );
+
+    assert!(matches!(
+        derive_address(
+            &publickey,
+            &AddressType::Cosmos(Some("Bad HRP".to_string()))
+        ),
+        Err(SignerError::InvalidArgument(_))
+    ));
+    assert!(derive_address(&[4; 65], &AddressType::Tron).is_err());
+}
+
+#[test]
+fn test_eos_dev_key() {
+    let privkey = crate::types::ECDSAPrivateKey::from_string(
+        "d2653ff7cbb2d8ff129ac27ef5781ce68b2558c41a74af1f2ddca635cbeef07d",
+    )
+    .unwrap();
+    let publickey = crate::types::PrivateKey::to_pubkey(&privkey).unwrap();
+    assert_eq!(
+        derive_address(&publickey, &AddressType::EosLegacy).unwrap(),
+        "EOS6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5GDW5CV"
+    );
+}
@@ -0,0 +1,249 @@
+use crate::error::SignerError;
+use crate::management::{
+    ECDSAPublicKeyArgs, EcdsaCurve, EcdsaKeyId, ManagementCanister, SignWithECDSA,
+};
+use crate::state::State;
+use crate::types::{
+    Bundle, ECDSAPrivateKey, ECDSAPublicKey, HashAlgorithm, IcPublicKey, PrivateKey, PublicKey,
+    SignatureFormat,
+};
+use crate::utils::{make_recoverable, verify_signature};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use zeroize::Zeroizing;
+
+/// Which key a signature is made with, as passed to `sign`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum KeyDescriptor {
+    /// The key ID or alias of a key stored in the canister
+    Local(String),
+    /// The sub-path of a threshold ECDSA key of the caller
+    Threshold(Vec<Vec<u8>>),
+}
+
+/// A secp256k1 key which signs 32-byte digests. Bundles of every backend carry the
+/// uncompressed SEC1 key and an r||s||v signature.
+pub trait SigningBackend {
+    /// SEC1, compressed or not
+    async fn public_key(&self) -> Result<Vec<u8>, SignerError>;
+
+    /// r||s or r||s||v over `msg_hash`, which was computed with `hash_algo`
+    async fn sign_digest(
+        &self,
+        msg_hash: &[u8],
+        hash_algo: HashAlgorithm,
+    ) -> Result<Vec<u8>, SignerError>;
+
+    // Checks the signature before handing it out
+    async fn sign(&self, msg_hash: &[u8], hash_algo: HashAlgorithm) -> Result<Bundle, SignerError> {
+        if msg_hash.len() != 32 {
+            return Err(SignerError::InvalidLength("message hash".to_string()));
+        }
+        let publickey = self.public_key().await?;
+        let signature = self.sign_digest(msg_hash, hash_algo).await?;
+        signature_bundle(msg_hash.to_vec(), signature, &publickey, hash_algo)
+    }
+}
+
+pub fn signature_bundle(
+    msg_hash: Vec<u8>,
+    signature: Vec<u8>,
+    publickey: &[u8],
+    hash_algo: HashAlgorithm,
+) -> Result<Bundle, SignerError> {
+    let publickey = ECDSAPublicKey::from_vec8(publickey)?.to_vec8();
+    // Also normalizes a high s, which `verify_signature` rejects
+    let signature = match signature.len() {
+        64 => make_recoverable(&msg_hash, &signature, &publickey)?,
+        _ => signature,
+    };
+    if !verify_signature(&msg_hash, &signature, &publickey, hash_algo) {
+        return Err(SignerError::VerificationFailed);
+    }
+    Ok(Bundle {
+        digest: msg_hash,
+        publickey,
+        signature,
+        hash_algorithm: hash_algo,
+        signature_format: SignatureFormat::Recoverable,
+    })
+}
+
+/// A private key kept in the canister
+pub struct LocalKey {
+    privkey: Zeroizing<ECDSAPrivateKey>,
+}
+
+impl LocalKey {
+    pub fn new(privkey: Zeroizing<ECDSAPrivateKey>) -> LocalKey {
+        LocalKey { privkey }
+    }
+}
+
+impl SigningBackend for LocalKey {
+    async fn public_key(&self) -> Result<Vec<u8>, SignerError> {
+        self.privkey.to_pubkey()
+    }
+
+    async fn sign_digest(
+        &self,
+        msg_hash: &[u8],
+        hash_algo: HashAlgorithm,
+    ) -> Result<Vec<u8>, SignerError> {
+        self.privkey.sign(msg_hash, hash_algo)
+    }
+}
+
+/// A threshold ECDSA key of the subnet, derived at `derivation_path`. The digest is
+/// signed as is, `hash_algo` is only recorded in the bundle.
+pub struct ThresholdKey<'a, M> {
+    mgmt: &'a M,
+    derivation_path: Vec<Vec<u8>>,
+}
+
+impl<'a, M: ManagementCanister> ThresholdKey<'a, M> {
+    /// `derivation_path` is the full path, starting with the principal it belongs to
+    pub fn new(mgmt: &'a M, derivation_path: Vec<Vec<u8>>) -> ThresholdKey<'a, M> {
+        ThresholdKey {
+            mgmt,
+            derivation_path,
+        }
+    }
+
+    // Derived keys never change, so they are only requested once per key name and path
+    pub async fn ic_public_key(&self) -> Result<IcPublicKey, SignerError> {
+        let key_id = ecdsa_key_id();
+        if let Some(key) = State::get_ic_public_key(&key_id.name, &self.derivation_path) {
+            return Ok(key);
+        }
+        let request = ECDSAPublicKeyArgs {
+            canister_id: None,
+            derivation_path: self.derivation_path.clone(),
+            key_id: key_id.clone(),
+        };
+        let res = self.mgmt.ecdsa_public_key(request).await?;
+        let key = IcPublicKey {
+            public_key: res.public_key,
+            chain_code: res.chain_code,
+        };
+        State::cache_ic_public_key(&key_id.name, &self.derivation_path, &key);
+        Ok(key)
+    }
+}
+
+impl<M: ManagementCanister> SigningBackend for ThresholdKey<'_, M> {
+    async fn public_key(&self) -> Result<Vec<u8>, SignerError> {
+        Ok(self.ic_public_key().await?.public_key)
+    }
+
+    async fn sign_digest(
+        &self,
+        msg_hash: &[u8],
+        _hash_algo: HashAlgorithm,
+    ) -> Result<Vec<u8>, SignerError> {
+        let request = SignWithECDSA {
+            message_hash: msg_hash.to_vec(),
+            derivation_path: self.derivation_path.clone(),
+            key_id: ecdsa_key_id(),
+        };
+        Ok(self.mgmt.sign_with_ecdsa(request).await?.signature)
+    }
+}
+
+fn ecdsa_key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: State::ecdsa_key_name(),
+    }
+}
+
+// Polls a future which never waits, as those of `LocalKey` or of a cached key
+#[cfg(test)]
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    match std::pin::pin!(future).poll(&mut context) {
+        std::task::Poll::Ready(output) => output,
+        std::task::Poll::Pending => panic!("future is not ready"),
+    }
+}
+
+#[test]
+fn test_local_key() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let digest = crate::utils::hexstr_to_vec(
+        "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2",
+    )
+    .unwrap();
+    let key = LocalKey::new(Zeroizing::new(
+        ECDSAPrivateKey::from_string(privkey).unwrap(),
+    ));
+    let bundle = block_on(key.sign(&digest, HashAlgorithm::SHA2_256)).unwrap();
+    assert_eq!(bundle.publickey, block_on(key.public_key()).unwrap());
+    assert_eq!(bundle.signature.len(), 65);
+    assert_eq!(bundle.hash_algorithm, HashAlgorithm::SHA2_256);
+    assert!(verify_signature(
+        &digest,
+        &bundle.signature,
+        &bundle.publickey,
+        HashAlgorithm::SHA2_256
+    ));
+    assert!(matches!(
+        block_on(key.sign(&digest[1..], HashAlgorithm::SHA2_256)),
+        Err(SignerError::InvalidLength(_))
+    ));
+
+    // r||s as returned by threshold ECDSA gets its recovery id back
+    let bundle2 = signature_bundle(
+        digest.clone(),
+        bundle.signature[..64].to_vec(),
+        &bundle.publickey,
+        HashAlgorithm::SHA2_256,
+    )
+    .unwrap();
+    assert_eq!(bundle2.signature, bundle.signature);
+
+    // Threshold signatures with a high s are normalized rather than rejected
+    let signature = <k256::ecdsa::Signature as k256::ecdsa::signature::Signature>::from_bytes(
+        &bundle.signature[..64],
+    )
+    .unwrap();
+    let (r, s) = signature.split_scalars();
+    let high_s = k256::ecdsa::Signature::from_scalars(r, -s).unwrap();
+    let bundle3 = signature_bundle(
+        digest,
+        high_s.as_ref().to_vec(),
+        &bundle.publickey,
+        HashAlgorithm::SHA2_256,
+    )
+    .unwrap();
+    assert_eq!(bundle3.signature, bundle.signature);
+}
+
+#[test]
+fn test_ecdsa_key_id() {
+    use crate::management::MockManagementCanister;
+    use crate::state::DEFAULT_ECDSA_KEY_NAME;
+
+    let mgmt = MockManagementCanister::new(b"seed");
+    let path = vec![vec![1; 29], vec![7]];
+    let key = ThresholdKey::new(&mgmt, path.clone());
+    let default_key = block_on(key.public_key()).unwrap();
+    assert_eq!(ecdsa_key_id().name, DEFAULT_ECDSA_KEY_NAME);
+
+    State::set_ecdsa_key_name("test_key_1").unwrap();
+    assert_eq!(ecdsa_key_id().name, "test_key_1");
+    let test_key = block_on(key.public_key()).unwrap();
+    assert_ne!(test_key, default_key);
+    let digest = crate::utils::hash_sha256(b"Hello world");
+    let bundle = block_on(key.sign(&digest, HashAlgorithm::SHA2_256)).unwrap();
+    assert_eq!(
+        bundle.publickey,
+        ECDSAPublicKey::from_vec8(&test_key).unwrap().to_vec8()
+    );
+
+    // Public keys are cached per key name
+    let cached = |name| State::get_ic_public_key(name, &path).unwrap().public_key;
+    assert_eq!(cached(DEFAULT_ECDSA_KEY_NAME), default_key);
+    assert_eq!(cached("test_key_1"), test_key);
+    State::set_ecdsa_key_name(DEFAULT_ECDSA_KEY_NAME).unwrap();
+    assert_eq!(block_on(key.public_key()).unwrap(), default_key);
+}
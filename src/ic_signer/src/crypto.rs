@@ -1,3 +1,8 @@
+use crate::error::SignerError;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
 use k256::ecdsa::{
     digest::BlockInput,
     signature::digest::{
@@ -5,12 +10,47 @@ use k256::ecdsa::{
         FixedOutputDirty, Reset, Update,
     },
 };
-use sha3::Digest;
+use sha2::Sha256;
+use sha3::{Digest, Keccak256, Sha3_256};
 use std::marker::PhantomData;
+use subtle::ConstantTimeEq;
 
 const HASH_256_MSG_LEN: usize = 32;
+pub const KEK_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+
+pub type Blake2b256 = blake2::Blake2b<blake2::digest::consts::U32>;
+
+/// A hash function with a 256-bit output.
+pub trait Hasher256 {
+    fn hash(data: &[u8]) -> [u8; 32];
+}
+
+macro_rules! impl_hasher256 {
+    ($($type:ty),*) => {
+        $(
+            impl Hasher256 for $type {
+                fn hash(data: &[u8]) -> [u8; 32] {
+                    <$type as Digest>::digest(data).into()
+                }
+            }
+        )*
+    };
+}
 
-// Hash256 includes sha3::{Keccak256, Sha3_256}
+impl_hasher256!(Sha256, Sha3_256, Keccak256, Blake2b256);
+
+/// SHA-256 applied twice, as used by Bitcoin
+#[derive(Clone, Default)]
+pub struct DoubleSha256;
+
+impl Hasher256 for DoubleSha256 {
+    fn hash(data: &[u8]) -> [u8; 32] {
+        Sha256::hash(&Sha256::hash(data))
+    }
+}
+
+// Hash256 includes any Hasher256, e.g. sha3::{Keccak256, Sha3_256}
 #[derive(Clone)]
 pub struct Hash256<A> {
     msg: Vec<u8>,
@@ -40,11 +80,11 @@ impl<A> Reset for Hash256<A> {
 }
 
 impl<A> TryFrom<&[u8]> for Hash256<A> {
-    type Error = &'static str;
+    type Error = SignerError;
 
     fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
         if input.len() != HASH_256_MSG_LEN {
-            Err("The length of message hash error")
+            Err(SignerError::InvalidLength("message hash".to_string()))
         } else {
             let mut arr = [0; 32];
             arr.copy_from_slice(input);
@@ -64,13 +104,72 @@ impl<A> Update for Hash256<A> {
     }
 }
 
-impl<A: Digest> FixedOutputDirty for Hash256<A> {
+impl<A: Hasher256> FixedOutputDirty for Hash256<A> {
     type OutputSize = U32;
     fn finalize_into_dirty(&mut self, out: &mut GenericArray<u8, Self::OutputSize>) {
         if self.hash != <[u8; 32]>::default() {
             out.copy_from_slice(&self.hash);
         } else {
-            out.copy_from_slice(&<A as Digest>::digest(&self.msg))
+            out.copy_from_slice(&A::hash(&self.msg))
         }
     }
-}
\ No newline at end of file
+}
+
+// Seal/open data with AES-256-GCM, `aad` binds the ciphertext to where it is stored
+pub fn seal(
+    kek: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, SignerError> {
+    let cipher = aead_cipher(kek, nonce)?;
+    cipher
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| SignerError::Crypto("Failed to seal data".to_string()))
+}
+
+pub fn open(
+    kek: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, SignerError> {
+    let cipher = aead_cipher(kek, nonce)?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| SignerError::Crypto("Failed to open sealed data".to_string()))
+}
+
+pub fn salted_hash(salt: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    Digest::update(&mut hasher, salt);
+    Digest::update(&mut hasher, data);
+    hasher.finalize().into()
+}
+
+// Compares secrets without leaking where they differ through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+fn aead_cipher(kek: &[u8], nonce: &[u8]) -> Result<Aes256Gcm, SignerError> {
+    if kek.len() != KEK_LEN || nonce.len() != NONCE_LEN {
+        return Err(SignerError::InvalidLength(
+            "key-encryption key or nonce".to_string(),
+        ));
+    }
+    Aes256Gcm::new_from_slice(kek)
+        .map_err(|_| SignerError::InvalidKey("key-encryption key".to_string()))
+}
@@ -0,0 +1,87 @@
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use std::fmt;
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SignerError {
+    /// A hex string argument could not be decoded
+    InvalidHex,
+    /// A base64 string argument could not be decoded
+    InvalidBase64,
+    /// A key, digest or signature has the wrong length
+    InvalidLength(String),
+    InvalidKey(String),
+    /// An argument is out of the accepted range or format
+    InvalidArgument(String),
+    KeyNotFound,
+    KeyIdExists,
+    AliasExists,
+    /// Aliases must be non-empty and not all digits, so they can't be taken for a key ID
+    InvalidAlias(String),
+    KeyDisabled,
+    KeyArchived,
+    /// The key is not allowed to sign with the requested hash algorithm
+    HashAlgorithmNotAllowed,
+    ApiKeyNotFound,
+    ApiKeyExists,
+    ApiKeyExpired,
+    /// The API key is not scoped for the requested key, method or hash algorithm
+    OutOfApiKeyScope(String),
+    Unauthorized,
+    /// Signing with a raw private key is disabled by the canister configuration
+    RawPrivkeyDisabled,
+    /// No key-encryption key has been generated to seal private keys with
+    NoKeyEncryptionKey,
+    Crypto(String),
+    VerificationFailed,
+    /// The canister holds fewer cycles than the fee of a threshold signature
+    InsufficientCycles(u64),
+    /// A call to the management canister was rejected
+    ManagementCanister(String),
+    Storage(String),
+}
+
+impl fmt::Display for SignerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignerError::InvalidHex => write!(f, "Failed to decode from hex string"),
+            SignerError::InvalidBase64 => write!(f, "Failed to decode from base64 string"),
+            SignerError::InvalidLength(what) => write!(f, "The length of {} error", what),
+            SignerError::InvalidKey(reason) => write!(f, "Invalid key: {}", reason),
+            SignerError::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+            SignerError::KeyNotFound => write!(f, "Key ID not found"),
+            SignerError::KeyIdExists => write!(f, "This key ID is already exist"),
+            SignerError::AliasExists => write!(f, "This alias is already in use"),
+            SignerError::InvalidAlias(alias) => write!(f, "Invalid alias: {}", alias),
+            SignerError::KeyDisabled => write!(f, "Key is disabled"),
+            SignerError::KeyArchived => write!(f, "Key is archived"),
+            SignerError::HashAlgorithmNotAllowed => {
+                write!(f, "Hash algorithm is not allowed for this key")
+            }
+            SignerError::ApiKeyNotFound => write!(f, "API key not found"),
+            SignerError::ApiKeyExists => write!(f, "An API key with this name already exists"),
+            SignerError::ApiKeyExpired => write!(f, "API key has expired"),
+            SignerError::OutOfApiKeyScope(what) => {
+                write!(f, "API key is not allowed to use this {}", what)
+            }
+            SignerError::Unauthorized => write!(f, "Caller is not authorized"),
+            SignerError::RawPrivkeyDisabled => write!(
+                f,
+                "Signing with a raw private key is disabled, use a key ID and an API key"
+            ),
+            SignerError::NoKeyEncryptionKey => {
+                write!(f, "No key-encryption key has been generated")
+            }
+            SignerError::Crypto(reason) => write!(f, "{}", reason),
+            SignerError::VerificationFailed => write!(f, "Signature verified failed"),
+            SignerError::InsufficientCycles(fee) => write!(
+                f,
+                "The canister needs at least {} cycles to sign with a threshold key",
+                fee
+            ),
+            SignerError::ManagementCanister(reason) => {
+                write!(f, "Management canister call failed: {}", reason)
+            }
+            SignerError::Storage(reason) => write!(f, "Storage error: {}", reason),
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use crate::error::SignerError;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// Application errors, in the range reserved for implementation-defined server errors
+pub const SERVER_ERROR: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+pub const KEY_NOT_FOUND: i64 = -32002;
+pub const KEY_UNAVAILABLE: i64 = -32003;
+pub const FORBIDDEN: i64 = -32004;
+
+const VERSION: &str = "2.0";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Id {
+    Number(serde_json::Number),
+    String(String),
+    Null,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Params {
+    Positional(Vec<Value>),
+    Named(Map<String, Value>),
+}
+
+/// The params of a method. `#[serde(deny_unknown_fields)]` has no effect next to
+/// `#[serde(flatten)]`, so `Params::parse` checks the named params against `FIELDS`.
+pub trait MethodParams: DeserializeOwned {
+    /// Every param name the method accepts
+    const FIELDS: &'static [&'static str];
+}
+
+impl Params {
+    /// Deserializes the params into `T`, positional params are matched to the
+    /// fields of `T` through `names`. Unknown named params are rejected.
+    pub fn parse<T: MethodParams>(&self, names: &[&str]) -> Result<T, ErrorObject> {
+        let named = match self {
+            Params::Named(map) => {
+                if let Some(name) = map.keys().find(|name| !T::FIELDS.contains(&name.as_str())) {
+                    return Err(ErrorObject::invalid_params(&format!(
+                        "unknown param `{}`",
+                        name
+                    )));
+                }
+                map.clone()
+            }
+            Params::Positional(values) => {
+                if values.len() > names.len() {
+                    return Err(ErrorObject::invalid_params("too many params"));
+                }
+                names
+                    .iter()
+                    .map(|name| name.to_string())
+                    .zip(values.iter().cloned())
+                    .collect()
+            }
+        };
+        serde_json::from_value(Value::Object(named))
+            .map_err(|e| ErrorObject::invalid_params(&e.to_string()))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Params::Positional(values) => values.len(),
+            Params::Named(map) => map.len(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: Option<Params>,
+    /// `None` for a notification, which gets no response
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub id: Option<Id>,
+}
+
+// Keeps an explicit `"id": null` apart from a missing id
+fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Id>, D::Error> {
+    Id::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl ErrorObject {
+    pub fn new(code: i64, message: &str) -> ErrorObject {
+        ErrorObject {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn parse_error() -> ErrorObject {
+        ErrorObject::new(PARSE_ERROR, "Parse error")
+    }
+
+    pub fn invalid_request() -> ErrorObject {
+        ErrorObject::new(INVALID_REQUEST, "Invalid Request")
+    }
+
+    pub fn method_not_found(method: &str) -> ErrorObject {
+        ErrorObject {
+            data: Some(Value::String(method.to_string())),
+            ..ErrorObject::new(METHOD_NOT_FOUND, "Method not found")
+        }
+    }
+
+    pub fn invalid_params(reason: &str) -> ErrorObject {
+        ErrorObject {
+            data: Some(Value::String(reason.to_string())),
+            ..ErrorObject::new(INVALID_PARAMS, "Invalid params")
+        }
+    }
+}
+
+impl From<SignerError> for ErrorObject {
+    fn from(e: SignerError) -> Self {
+        let code = match e {
+            SignerError::InvalidHex
+            | SignerError::InvalidBase64
+            | SignerError::InvalidLength(_)
+            | SignerError::InvalidKey(_)
+            | SignerError::InvalidArgument(_)
+            | SignerError::InvalidAlias(_) => INVALID_PARAMS,
+            SignerError::ApiKeyNotFound | SignerError::ApiKeyExpired => UNAUTHORIZED,
+            SignerError::OutOfApiKeyScope(_)
+            | SignerError::Unauthorized
+            | SignerError::RawPrivkeyDisabled => FORBIDDEN,
+            SignerError::KeyNotFound => KEY_NOT_FOUND,
+            SignerError::KeyDisabled
+            | SignerError::KeyArchived
+            | SignerError::HashAlgorithmNotAllowed => KEY_UNAVAILABLE,
+            SignerError::NoKeyEncryptionKey
+            | SignerError::Crypto(_)
+            | SignerError::VerificationFailed
+            | SignerError::Storage(_) => INTERNAL_ERROR,
+            _ => SERVER_ERROR,
+        };
+        ErrorObject::new(code, &e.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorObject>,
+    pub id: Id,
+}
+
+impl Response {
+    fn new(id: Id, result: Result<Value, ErrorObject>) -> Response {
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(e) => (None, Some(e)),
+        };
+        Response {
+            jsonrpc: VERSION.to_string(),
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+/// The HTTP status of a single response failing with `code`.
+pub fn http_status(code: i64) -> u16 {
+    match code {
+        PARSE_ERROR | INVALID_REQUEST | INVALID_PARAMS => 400,
+        UNAUTHORIZED => 401,
+        FORBIDDEN | KEY_UNAVAILABLE => 403,
+        METHOD_NOT_FOUND | KEY_NOT_FOUND => 404,
+        _ => 500,
+    }
+}
+
+/// The HTTP status and body replied to a request.
+pub struct Reply {
+    pub status_code: u16,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    fn new(status_code: u16, value: Value) -> Reply {
+        match serde_json::to_vec(&value) {
+            Ok(body) => Reply { status_code, body },
+            Err(_) => Reply {
+                status_code: 500,
+                body: Vec::new(),
+            },
+        }
+    }
+
+    /// Fails the whole request, before any of it is handled.
+    pub fn error(status_code: u16, error: ErrorObject) -> Reply {
+        Reply::new(
+            status_code,
+            Value::from(Response::new(Id::Null, Err(error))),
+        )
+    }
+}
+
+/// A parsed request body. Its valid requests are dispatched by the caller, and
+/// the results passed back to `reply` in the same order.
+pub struct Batch {
+    is_batch: bool,
+    // Invalid requests are replaced by their ID, if it could be read
+    requests: Vec<Result<Request, Id>>,
+}
+
+impl Batch {
+    pub fn parse(body: &[u8]) -> Result<Batch, Reply> {
+        let (is_batch, values) = match serde_json::from_slice::<Value>(body) {
+            Err(_) => return Err(Reply::error(400, ErrorObject::parse_error())),
+            Ok(Value::Array(batch)) if batch.is_empty() => {
+                return Err(Reply::error(400, ErrorObject::invalid_request()))
+            }
+            Ok(Value::Array(batch)) => (true, batch),
+            Ok(request) => (false, vec![request]),
+        };
+        Ok(Batch {
+            is_batch,
+            requests: values.into_iter().map(parse_request).collect(),
+        })
+    }
+
+    /// The valid requests as `(method, params)`
+    pub fn calls(&self) -> impl Iterator<Item = (&str, &Params)> {
+        self.requests
+            .iter()
+            .filter_map(|request| request.as_ref().ok())
+            .map(|request| {
+                let params = request.params.as_ref().unwrap_or(&NO_PARAMS);
+                (request.method.as_str(), params)
+            })
+    }
+
+    /// A batch is replied with 200 however its requests fail, and 204 is replied
+    /// when the body only held notifications.
+    pub fn reply(self, results: Vec<Result<Value, ErrorObject>>) -> Reply {
+        let mut results = results.into_iter();
+        let responses: Vec<Response> = self
+            .requests
+            .into_iter()
+            .filter_map(|request| match request {
+                Ok(request) => {
+                    let result = results
+                        .next()
+                        .unwrap_or_else(|| Err(ErrorObject::new(INTERNAL_ERROR, "Not handled")));
+                    request.id.map(|id| Response::new(id, result))
+                }
+                Err(id) => Some(Response::new(id, Err(ErrorObject::invalid_request()))),
+            })
+            .collect();
+
+        if responses.is_empty() {
+            Reply {
+                status_code: 204,
+                body: Vec::new(),
+            }
+        } else if self.is_batch {
+            let responses = responses.into_iter().map(Value::from).collect();
+            Reply::new(200, Value::Array(responses))
+        } else {
+            let response = responses.into_iter().next().unwrap();
+            let status_code = response
+                .error
+                .as_ref()
+                .map_or(200, |error| http_status(error.code));
+            Reply::new(status_code, Value::from(response))
+        }
+    }
+}
+
+static NO_PARAMS: Params = Params::Positional(Vec::new());
+
+// The ID is read on its own first, so it's echoed even if the rest is malformed
+fn parse_request(request: Value) -> Result<Request, Id> {
+    let id = request
+        .get("id")
+        .and_then(|id| serde_json::from_value(id.clone()).ok())
+        .unwrap_or(Id::Null);
+    let request: Request = serde_json::from_value(request).map_err(|_| id.clone())?;
+    if request.jsonrpc != VERSION {
+        return Err(id);
+    }
+    Ok(request)
+}
+
+impl From<Response> for Value {
+    fn from(response: Response) -> Self {
+        serde_json::to_value(response).unwrap_or(Value::Null)
+    }
+}
+
+#[cfg(test)]
+fn test_dispatch(method: &str, params: &Params) -> Result<Value, ErrorObject> {
+    #[derive(Deserialize)]
+    struct EchoParams {
+        text: String,
+    }
+
+    impl MethodParams for EchoParams {
+        const FIELDS: &'static [&'static str] = &["text"];
+    }
+
+    match method {
+        "echo" => params
+            .parse::<EchoParams>(&["text"])
+            .map(|params| Value::String(params.text)),
+        "fail" => Err(SignerError::KeyNotFound.into()),
+        _ => Err(ErrorObject::method_not_found(method)),
+    }
+}
+
+#[cfg(test)]
+fn handle(body: &[u8]) -> Reply {
+    match Batch::parse(body) {
+        Ok(batch) => {
+            let results = batch
+                .calls()
+                .map(|(method, params)| test_dispatch(method, params))
+                .collect();
+            batch.reply(results)
+        }
+        Err(reply) => reply,
+    }
+}
+
+#[cfg(test)]
+fn test_handle(body: &str) -> Option<Value> {
+    let reply = handle(body.as_bytes());
+    if reply.status_code == 204 {
+        assert!(reply.body.is_empty());
+        return None;
+    }
+    Some(serde_json::from_slice(&reply.body).unwrap())
+}
+
+#[test]
+fn test_single_request() {
+    let reply = test_handle(r#"{"jsonrpc":"2.0","method":"echo","params":["hi"],"id":1}"#);
+    assert_eq!(
+        reply.unwrap(),
+        serde_json::json!({"jsonrpc": "2.0", "result": "hi", "id": 1})
+    );
+
+    let reply = test_handle(r#"{"jsonrpc":"2.0","method":"echo","params":{"text":"hi"},"id":"a"}"#);
+    assert_eq!(
+        reply.unwrap(),
+        serde_json::json!({"jsonrpc": "2.0", "result": "hi", "id": "a"})
+    );
+
+    let reply = test_handle(r#"{"jsonrpc":"2.0","method":"fail","id":null}"#).unwrap();
+    assert_eq!(reply["id"], Value::Null);
+    assert_eq!(reply["error"]["code"], KEY_NOT_FOUND);
+    assert!(reply.get("result").is_none());
+
+    assert!(test_handle(r#"{"jsonrpc":"2.0","method":"echo","params":["hi"]}"#).is_none());
+}
+
+#[test]
+fn test_request_errors() {
+    let code = |body: &str| test_handle(body).unwrap()["error"]["code"].clone();
+    assert_eq!(code(r#"{"jsonrpc":"2.0","method":"echo""#), PARSE_ERROR);
+    assert_eq!(
+        code(r#"{"jsonrpc":"1.0","method":"echo","id":1}"#),
+        INVALID_REQUEST
+    );
+    assert_eq!(
+        code(r#"{"jsonrpc":"2.0","method":1,"id":1}"#),
+        INVALID_REQUEST
+    );
+    // Invalid requests are still answered with their ID
+    for (body, id) in [
+        (
+            r#"{"jsonrpc":"2.0","method":1,"id":7}"#,
+            serde_json::json!(7),
+        ),
+        (
+            r#"{"jsonrpc":"2.0","params":[],"id":"a"}"#,
+            serde_json::json!("a"),
+        ),
+        (
+            r#"{"jsonrpc":"1.0","method":"echo","id":8}"#,
+            serde_json::json!(8),
+        ),
+        (r#"{"jsonrpc":"2.0","method":1,"id":{}}"#, Value::Null),
+    ] {
+        assert_eq!(test_handle(body).unwrap()["id"], id);
+    }
+    assert_eq!(code(r#"[]"#), INVALID_REQUEST);
+    assert_eq!(
+        code(r#"{"jsonrpc":"2.0","method":"nope","id":1}"#),
+        METHOD_NOT_FOUND
+    );
+    assert_eq!(
+        code(r#"{"jsonrpc":"2.0","method":"echo","params":[1],"id":1}"#),
+        INVALID_PARAMS
+    );
+    assert_eq!(
+        code(r#"{"jsonrpc":"2.0","method":"echo","params":["a","b"],"id":1}"#),
+        INVALID_PARAMS
+    );
+    assert_eq!(
+        code(r#"{"jsonrpc":"2.0","method":"echo","params":{"text":"a","txt":"b"},"id":1}"#),
+        INVALID_PARAMS
+    );
+}
+
+#[test]
+fn test_batch_request() {
+    let reply = test_handle(
+        r#"[
+            {"jsonrpc":"2.0","method":"echo","params":["a"],"id":1},
+            {"jsonrpc":"2.0","method":"echo","params":["b"]},
+            {"foo":"bar"},
+            {"jsonrpc":"2.0","method":"echo","params":["c"],"id":2}
+        ]"#,
+    )
+    .unwrap();
+    let replies = reply.as_array().unwrap();
+    assert_eq!(replies.len(), 3);
+    assert_eq!(replies[0]["result"], "a");
+    assert_eq!(replies[1]["error"]["code"], INVALID_REQUEST);
+    assert_eq!(replies[2]["id"], 2);
+
+    assert!(test_handle(r#"[{"jsonrpc":"2.0","method":"echo","params":["a"]}]"#).is_none());
+}
+
+#[test]
+fn test_http_status() {
+    let status = |body: &str| handle(body.as_bytes()).status_code;
+    assert_eq!(handle(b"\xff\xfe").status_code, 400);
+    assert_eq!(
+        status(r#"{"jsonrpc":"2.0","method":"echo","params":["a"],"id":1}"#),
+        200
+    );
+    assert_eq!(
+        status(r#"{"jsonrpc":"2.0","method":"echo","params":["a"]}"#),
+        204
+    );
+    assert_eq!(status(r#"{"jsonrpc":"2.0","method":"nope","id":1}"#), 404);
+    assert_eq!(status(r#"{"jsonrpc":"2.0","method":"fail","id":1}"#), 404);
+    assert_eq!(status(r#"[{"jsonrpc":"2.0","method":"fail","id":1}]"#), 200);
+    assert_eq!(
+        http_status(ErrorObject::from(SignerError::ApiKeyNotFound).code),
+        401
+    );
+    assert_eq!(
+        http_status(ErrorObject::from(SignerError::OutOfApiKeyScope("key".to_string())).code),
+        403
+    );
+    assert_eq!(
+        http_status(ErrorObject::from(SignerError::VerificationFailed).code),
+        500
+    );
+}
@@ -1,9 +1,32 @@
+mod address;
+mod backend;
 mod crypto;
+mod error;
+mod jsonrpc;
+mod management;
+mod state;
 mod types;
 mod utils;
 
-use types::{Bundle, ECDSAPrivateKey, HashAlgorithm, PrivateKey};
-use utils::{hash_keccak256, hash_sha256, hexstr_to_vec, vec8_to_hexstr, verify_signature};
+use address::{derive_address, eth_address};
+use backend::{signature_bundle, KeyDescriptor, LocalKey, SigningBackend, ThresholdKey};
+use error::SignerError;
+use jsonrpc::{Batch, ErrorObject, MethodParams, Params, Reply};
+#[cfg(test)]
+use management::MockManagementCanister;
+use management::{Ic00, ManagementCanister};
+use state::State;
+use types::{
+    AddressType, ApiKeyInfo, ApiKeyScope, Bundle, Curve, ECDSAPrivateKey, EncodedPublicKey,
+    HashAlgorithm, IcPublicKey, KeyInfo, KeyMigration, KeyStatus, MessageEncoding, PrivateKey,
+    PublicKey, PublicKeyEncoding, SignatureFormat, Verification,
+};
+use utils::{check_signature, hexstr_to_vec, recover_pubkey, vec8_to_hexstr};
+#[cfg(test)]
+use utils::{
+    hash_blake2b_256, hash_double_sha256, hash_keccak256, hash_sha256, hash_sha3_256,
+    make_recoverable,
+};
 // use k256::sha2::{Sha256, Sha512, Digest};
 
 // use ic_cdk::api::call::CallResult;
@@ -11,28 +34,60 @@ use ic_cdk::{
     api,
     export::{
         candid::{CandidType, Deserialize, Func, Nat},
-        serde::{Deserialize as SerdeDeserialize, Serialize},
         Principal,
     },
-    storage,
 };
-use serde_json;
-use std::{cell::RefCell, collections::BTreeMap, mem};
+use serde_json::Value;
+use zeroize::Zeroizing;
 // use ic_certified_map::Hash;
 
-// #[derive(CandidType, Deserialize)]
-// struct StableState {
-//     state: State,
-//     // hashes: Vec<(String, Hash)>,
-// }
+#[derive(Clone, CandidType, Deserialize, Default)]
+struct InitArgs {
+    /// Allowed to call the admin methods, the installing principal by default. Under
+    /// `dfx deploy` that is the wallet canister rather than the developer.
+    owner: Option<Principal>,
+    /// Lets the HTTP gateway sign with raw private keys passed in the params
+    raw_privkey_enabled: Option<bool>,
+    /// The threshold ECDSA key to sign with, e.g. `test_key_1` or `key_1`
+    ecdsa_key_name: Option<String>,
+}
 
-// #[ic_cdk_macros::pre_upgrade]
-// fn pre_upgrade() {
-//     let state = STATE.with(|state| mem::take(&mut *state.borrow_mut()));
-//     // let hashes =
-//     let stable_state = StableState { state };
-//     storage::stable_save((stable_state,)).unwrap();
-// }
+impl InitArgs {
+    fn apply(&self) {
+        if let Some(owner) = &self.owner {
+            if *owner == Principal::anonymous() {
+                ic_cdk::trap("The owner can't be the anonymous principal");
+            }
+            State::set_owner(owner);
+        }
+        if let Some(enabled) = self.raw_privkey_enabled {
+            State::set_raw_privkey_enabled(enabled);
+        }
+        if let Some(name) = &self.ecdsa_key_name {
+            if let Err(e) = State::set_ecdsa_key_name(name) {
+                ic_cdk::trap(&e.to_string());
+            }
+        }
+    }
+}
+
+#[ic_cdk_macros::init]
+fn init(args: Option<InitArgs>) {
+    State::set_owner(&api::caller());
+    args.unwrap_or_default().apply();
+}
+
+// Settings missing from `args` are left as they were
+#[ic_cdk_macros::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    // The key store lives in stable structures, only the layout of older
+    // releases has to be imported
+    State::restore_legacy_layout();
+    if State::get_owner().is_none() {
+        State::set_owner(&api::caller());
+    }
+    args.unwrap_or_default().apply();
+}
 
 #[derive(Clone, CandidType, Deserialize)]
 struct HttpHeader(String, String);
@@ -68,6 +123,9 @@ struct HttpResponse {
     upgrade: Option<bool>,
 }
 
+// Large enough for a batch of a few hundred signing requests
+const MAX_REQUEST_BODY_LEN: usize = 64 * 1024;
+
 #[derive(CandidType, Deserialize)]
 struct HttpRequest {
     url: String,
@@ -76,224 +134,836 @@ struct HttpRequest {
     headers: Vec<HttpHeader>,
 }
 
-#[derive(serde::Deserialize)]
-struct JsonRPC {
-    id: String,
-    method: String,
-    params: Vec<String>,
-}
-
-// curl http://localhost:8000/?canisterId=rrkah-fqaaa-aaaaa-aaaaq-cai
+// curl -X POST -d '{"jsonrpc":"2.0","method":"sign_digest","params":[..],"id":1}' \
+//   http://localhost:8000/?canisterId=rrkah-fqaaa-aaaaa-aaaaq-cai
 #[ic_cdk_macros::query]
 fn http_request(request: HttpRequest) -> HttpResponse {
-    let mut status_code = 404;
-    let mut res_body = String::new();
-    let mut id = String::new();
-    let mut result = String::new();
-
-    if request.method.to_ascii_lowercase() == "post" {
-        status_code = 400;
-        let get_privkey = |params: &Vec<String>| {
-            if params.len() > 2 {
-                let api_key = &params[2];
-                let caller = State::get_caller_by_apikey(api_key).unwrap();
-                State::get_privkey(&caller, &params[0]).unwrap()
-            } else {
-                params[0].clone()
-            }
+    serve_http_request(request, api::time())
+}
+
+// `now` checks the expiry of API keys
+fn serve_http_request(request: HttpRequest, now: u64) -> HttpResponse {
+    let batch = match parse_http_request(request) {
+        Ok(batch) => batch,
+        Err(reply) => return http_response(reply),
+    };
+    // Have the gateway send the request again as an update call
+    if batch.calls().any(|(method, _)| is_update_method(method)) {
+        return HttpResponse {
+            upgrade: Some(true),
+            ..http_response(Reply {
+                status_code: 200,
+                body: Vec::new(),
+            })
         };
+    }
+    let results = batch
+        .calls()
+        .map(|(method, params)| dispatch_rpc(method, params, now))
+        .collect();
+    http_response(batch.reply(results))
+}
 
-        let parse_res = &parse_request(&request);
-        match parse_res {
-            Ok(res) => {
-                let method = &res.method;
-                let params = &res.params;
-
-                let privkey = &get_privkey(params);
-                let digest = &params[1];
-
-                if method.to_ascii_lowercase() == "sign_digest" {
-                    let sig_info = sign_digest(digest, &privkey).unwrap();
-                    result = vec8_to_hexstr(&sig_info.signature);
-                    id = res.id.to_string();
-                    status_code = 200;
-                }
-            }
-            Err(e) => result = e.to_string(),
-        }
+#[ic_cdk_macros::update]
+async fn http_request_update(request: HttpRequest) -> HttpResponse {
+    let batch = match parse_http_request(request) {
+        Ok(batch) => batch,
+        Err(reply) => return http_response(reply),
+    };
+    let mut results = Vec::new();
+    for (method, params) in batch.calls() {
+        results.push(dispatch_rpc_update(&Ic00, method, params, api::time()).await);
     }
+    http_response(batch.reply(results))
+}
 
-    res_body = format!(
-        "{{\"code\":{}, \"id\":\"{}\", \"result\":\"{}\"}}\n",
-        status_code, id, result
-    );
-    let headers = [
-        HttpHeader(
+fn parse_http_request(request: HttpRequest) -> Result<Batch, Reply> {
+    if !request.method.eq_ignore_ascii_case("post") {
+        return Err(Reply::error(
+            404,
+            ErrorObject::new(jsonrpc::INVALID_REQUEST, "Only POST requests are served"),
+        ));
+    }
+    let body = request.body.unwrap_or_default();
+    if body.len() > MAX_REQUEST_BODY_LEN {
+        return Err(Reply::error(
+            413,
+            ErrorObject::new(jsonrpc::INVALID_REQUEST, "Request body too large"),
+        ));
+    }
+    Batch::parse(&body)
+}
+
+fn http_response(reply: Reply) -> HttpResponse {
+    let mut headers = vec![HttpHeader(
+        "content-length".to_string(),
+        reply.body.len().to_string(),
+    )];
+    if !reply.body.is_empty() {
+        headers.push(HttpHeader(
             "content-type".to_string(),
             "application/json; charset=utf-8".to_string(),
-        ),
-        HttpHeader(
-            "content-length".to_string(),
-            res_body.as_bytes().len().to_string(),
-        ),
-    ];
+        ));
+    }
     HttpResponse {
-        body: res_body.as_bytes().to_vec(),
-        headers: headers.to_vec(),
-        status_code,
+        body: reply.body,
+        headers,
+        status_code: reply.status_code,
         streaming_strategy: None,
         upgrade: Some(false),
     }
 }
 
-fn parse_request(request: &HttpRequest) -> Result<JsonRPC, String> {
-    match request.body.clone() {
-        Some(body) => {
-            let body_str = String::from_utf8(body).unwrap();
+// Methods which make calls to the management canister or change the state
+const UPDATE_METHODS: [&str; 4] = [
+    "sign",
+    "sign_digest_ic",
+    "generate_privkey",
+    "rotate_apikey",
+];
 
-            let json_parsed = serde_json::from_str(&body_str);
-            match json_parsed {
-                Ok(res) => {
-                    return Ok(res);
-                }
-                Err(_) => return Err("Failed to parse request body".to_string()),
-            };
-        }
-        _ => return Err("Empty request body".to_string()),
+fn is_update_method(method: &str) -> bool {
+    UPDATE_METHODS
+        .iter()
+        .any(|update_method| update_method.eq_ignore_ascii_case(method))
+}
+
+fn dispatch_rpc(method: &str, params: &Params, now: u64) -> Result<Value, ErrorObject> {
+    match method.to_ascii_lowercase().as_str() {
+        "sign_digest" => rpc_sign_digest(params, now),
+        "sign_message" => rpc_sign_message(params, now),
+        "verify_signature" => rpc_verify_signature(params, now),
+        "recover_public_key" => rpc_recover_public_key(params),
+        "get_address" => rpc_get_address(params, now),
+        _ => Err(ErrorObject::method_not_found(method)),
     }
 }
 
-#[derive(CandidType, Deserialize, Default)]
-struct State {
-    privkeys: BTreeMap<Principal, BTreeMap<String, String>>,
-    apiKeys: BTreeMap<Principal, String>,
+async fn dispatch_rpc_update(
+    mgmt: &impl ManagementCanister,
+    method: &str,
+    params: &Params,
+    now: u64,
+) -> Result<Value, ErrorObject> {
+    match method.to_ascii_lowercase().as_str() {
+        "sign" => rpc_sign(mgmt, params, now).await,
+        "sign_digest_ic" => rpc_sign_digest_ic(mgmt, params, now).await,
+        "generate_privkey" => rpc_generate_privkey(mgmt, params, now).await,
+        "rotate_apikey" => rpc_rotate_apikey(mgmt, params, now).await,
+        _ => dispatch_rpc(method, params, now),
+    }
 }
 
-thread_local! {
-    static STATE: RefCell<State> = RefCell::default();
+/// How the signature in a result is laid out and encoded, these can only be given by name.
+#[derive(serde::Deserialize)]
+struct SignatureOutput {
+    /// Defaults to the format of the bundle
+    signature_format: Option<SignatureFormat>,
+    #[serde(default)]
+    signature_encoding: MessageEncoding,
 }
 
-impl State {
-    pub fn get_apikey(principal: &Principal) -> Result<String, String> {
-        STATE.with(|state| {
-            let state = state.borrow();
-            match state.apiKeys.get(principal) {
-                Some(api_key) => Ok(api_key.clone()),
-                None => Err(" API key not found".to_string()),
-            }
-        })
+impl SignatureOutput {
+    fn encode(&self, bundle: Bundle) -> Result<String, SignerError> {
+        let format = self.signature_format.unwrap_or(bundle.signature_format);
+        let bundle = bundle.with_format(format)?;
+        Ok(self.signature_encoding.encode(&bundle.signature))
     }
+}
+
+/// Either a key of the API key owner, or a raw private key.
+#[derive(serde::Deserialize)]
+struct SignDigestParams {
+    digest: String,
+    /// The key ID or alias
+    key_id: Option<String>,
+    api_key: Option<String>,
+    privkey: Option<String>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(flatten)]
+    output: SignatureOutput,
+}
 
-    pub fn get_caller_by_apikey(apikey: &String) -> Option<Principal> {
-        STATE.with(|state| {
-            let state = state.borrow();
-            for (k, v) in &state.apiKeys {
-                if *v == *apikey { return Some(*k); }
+impl MethodParams for SignDigestParams {
+    const FIELDS: &'static [&'static str] = &[
+        "digest",
+        "key_id",
+        "api_key",
+        "privkey",
+        "hash_algorithm",
+        "signature_format",
+        "signature_encoding",
+    ];
+}
+
+// Positional params are `[key_id, digest, api_key, hash_algorithm]` or `[privkey, digest]`,
+// the hash algorithm of a raw private key can only be given by name
+fn rpc_sign_digest(params: &Params, now: u64) -> Result<Value, ErrorObject> {
+    let names: &[&str] = if params.len() == 2 {
+        &["privkey", "digest"]
+    } else {
+        &["key_id", "digest", "api_key", "hash_algorithm"]
+    };
+    let params: SignDigestParams = params.parse(names)?;
+    let hash_algo = params.hash_algorithm;
+    let privkey = rpc_privkey(
+        "sign_digest",
+        params.key_id,
+        params.api_key,
+        params.privkey,
+        hash_algo,
+        now,
+    )?;
+    let bundle = sign_digest(&params.digest, &privkey, hash_algo)?;
+    Ok(Value::String(params.output.encode(bundle)?))
+}
+
+/// Like `SignDigestParams`, with the message to be hashed in place of the digest.
+#[derive(serde::Deserialize)]
+struct SignMessageParams {
+    message: String,
+    #[serde(default)]
+    encoding: MessageEncoding,
+    key_id: Option<String>,
+    api_key: Option<String>,
+    privkey: Option<String>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(flatten)]
+    output: SignatureOutput,
+}
+
+impl MethodParams for SignMessageParams {
+    const FIELDS: &'static [&'static str] = &[
+        "message",
+        "encoding",
+        "key_id",
+        "api_key",
+        "privkey",
+        "hash_algorithm",
+        "signature_format",
+        "signature_encoding",
+    ];
+}
+
+// Positional params are `[key_id, message, api_key, hash_algorithm, encoding]` or
+// `[privkey, message]`
+fn rpc_sign_message(params: &Params, now: u64) -> Result<Value, ErrorObject> {
+    let names: &[&str] = if params.len() == 2 {
+        &["privkey", "message"]
+    } else {
+        &["key_id", "message", "api_key", "hash_algorithm", "encoding"]
+    };
+    let params: SignMessageParams = params.parse(names)?;
+    let hash_algo = params.hash_algorithm;
+    let message = params.encoding.decode(&params.message)?;
+    let privkey = rpc_privkey(
+        "sign_message",
+        params.key_id,
+        params.api_key,
+        params.privkey,
+        hash_algo,
+        now,
+    )?;
+    let bundle = sign_message(&message, &privkey, hash_algo)?;
+    Ok(serde_json::json!({
+        "digest": vec8_to_hexstr(&bundle.digest),
+        "signature": params.output.encode(bundle)?,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct VerifySignatureParams {
+    #[serde(flatten)]
+    args: VerifyArgs,
+    /// Required to look the public key up by `key_id`
+    api_key: Option<String>,
+}
+
+impl MethodParams for VerifySignatureParams {
+    const FIELDS: &'static [&'static str] = &[
+        "digest",
+        "message",
+        "encoding",
+        "signature",
+        "signature_encoding",
+        "publickey",
+        "key_id",
+        "hash_algorithm",
+        "api_key",
+    ];
+}
+
+// Positional params are `[publickey, digest, signature, hash_algorithm]`, a message or
+// a key ID can only be given by name
+fn rpc_verify_signature(params: &Params, now: u64) -> Result<Value, ErrorObject> {
+    let params: VerifySignatureParams =
+        params.parse(&["publickey", "digest", "signature", "hash_algorithm"])?;
+    let args = params.args;
+    let publickey = match (&args.publickey, &args.key_id, params.api_key) {
+        (Some(publickey), None, None) => hexstr_to_vec(publickey),
+        (None, Some(key_ref), Some(api_key)) => {
+            let hash_algo = args.hash_algorithm.unwrap_or_default();
+            let (caller, key_id) =
+                authorize_apikey(&api_key, "verify_signature", key_ref, Some(hash_algo), now)?;
+            State::get_key_info(&caller, &key_id).map(|info| info.publickey)
+        }
+        _ => {
+            return Err(ErrorObject::invalid_params(
+                "expected either `publickey`, or `key_id` and `api_key`",
+            ))
+        }
+    };
+    let verification = verify(&args, publickey);
+    Ok(serde_json::to_value(verification).unwrap_or_default())
+}
+
+#[derive(serde::Deserialize)]
+struct RecoverPublicKeyParams {
+    digest: String,
+    signature: String,
+}
+
+impl MethodParams for RecoverPublicKeyParams {
+    const FIELDS: &'static [&'static str] = &["digest", "signature"];
+}
+
+// Positional params are `[digest, signature]`
+fn rpc_recover_public_key(params: &Params) -> Result<Value, ErrorObject> {
+    let params: RecoverPublicKeyParams = params.parse(&["digest", "signature"])?;
+    let key = recover_public_key(params.digest, params.signature)?;
+    Ok(serde_json::json!({
+        "publickey": vec8_to_hexstr(&key.publickey),
+        "eth_address": key.eth_address,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct GetAddressParams {
+    key_id: String,
+    api_key: String,
+    address_type: AddressType,
+}
+
+impl MethodParams for GetAddressParams {
+    const FIELDS: &'static [&'static str] = &["key_id", "api_key", "address_type"];
+}
+
+// Positional params are `[key_id, api_key, address_type]`
+fn rpc_get_address(params: &Params, now: u64) -> Result<Value, ErrorObject> {
+    let params: GetAddressParams = params.parse(&["key_id", "api_key", "address_type"])?;
+    let (caller, key_id) =
+        authorize_apikey(&params.api_key, "get_address", &params.key_id, None, now)?;
+    let info = State::get_key_info(&caller, &key_id)?;
+    let address = derive_address(&info.publickey, &params.address_type)?;
+    Ok(Value::String(address))
+}
+
+// The private key to sign with, either a key of the API key owner or a raw private key
+fn rpc_privkey(
+    method: &str,
+    key_id: Option<String>,
+    api_key: Option<String>,
+    privkey: Option<String>,
+    hash_algo: HashAlgorithm,
+    now: u64,
+) -> Result<Zeroizing<ECDSAPrivateKey>, ErrorObject> {
+    match (key_id, api_key, privkey) {
+        (Some(key_ref), Some(api_key), None) => {
+            let (caller, key_id) =
+                authorize_apikey(&api_key, method, &key_ref, Some(hash_algo), now)?;
+            Ok(State::get_privkey(&caller, &key_id, hash_algo)?)
+        }
+        (None, None, Some(privkey)) => {
+            if !State::raw_privkey_enabled() {
+                return Err(SignerError::RawPrivkeyDisabled.into());
             }
-            None
-        })
+            Ok(Zeroizing::new(ECDSAPrivateKey::from_string(&privkey)?))
+        }
+        _ => Err(ErrorObject::invalid_params(
+            "expected either `key_id` and `api_key`, or `privkey`",
+        )),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SignDigestIcParams {
+    digest: String,
+    api_key: String,
+    hash_algorithm: Option<HashAlgorithm>,
+    /// Hex elements, appended to the path of the API key owner
+    #[serde(default)]
+    derivation_path: Vec<String>,
+    #[serde(flatten)]
+    output: SignatureOutput,
+}
+
+impl MethodParams for SignDigestIcParams {
+    const FIELDS: &'static [&'static str] = &[
+        "digest",
+        "api_key",
+        "hash_algorithm",
+        "derivation_path",
+        "signature_format",
+        "signature_encoding",
+    ];
+}
+
+// Positional params are `[digest, api_key, hash_algorithm, derivation_path]`
+async fn rpc_sign_digest_ic(
+    mgmt: &impl ManagementCanister,
+    params: &Params,
+    now: u64,
+) -> Result<Value, ErrorObject> {
+    let params: SignDigestIcParams =
+        params.parse(&["digest", "api_key", "hash_algorithm", "derivation_path"])?;
+    let hash_algo = params.hash_algorithm.unwrap_or_default();
+    let caller = authorize_threshold_apikey(&params.api_key, "sign_digest_ic", hash_algo, now)?;
+    let sub_path = params
+        .derivation_path
+        .iter()
+        .map(|element| hexstr_to_vec(element))
+        .collect::<Result<_, _>>()?;
+    let derivation_path = caller_derivation_path(&caller, sub_path)?;
+    let bundle = sign_with_threshold_key(
+        mgmt,
+        &params.digest,
+        Some(hash_algo),
+        params.output.signature_format,
+        derivation_path,
+    )
+    .await?;
+    // The bundle is already in the requested format
+    let signature = params.output.signature_encoding.encode(&bundle.signature);
+    Ok(Value::String(signature))
+}
+
+/// `KeyDescriptor` with the path elements in hex
+#[derive(serde::Deserialize)]
+enum RpcKeyDescriptor {
+    Local(String),
+    Threshold(Vec<String>),
+}
+
+#[derive(serde::Deserialize)]
+struct SignParams {
+    key: RpcKeyDescriptor,
+    digest: String,
+    api_key: String,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(flatten)]
+    output: SignatureOutput,
+}
+
+impl MethodParams for SignParams {
+    const FIELDS: &'static [&'static str] = &[
+        "key",
+        "digest",
+        "api_key",
+        "hash_algorithm",
+        "signature_format",
+        "signature_encoding",
+    ];
+}
+
+// Positional params are `[key, digest, api_key, hash_algorithm]`, where `key` is
+// `{"Local": key_id}` or `{"Threshold": [hex, ...]}`
+async fn rpc_sign(
+    mgmt: &impl ManagementCanister,
+    params: &Params,
+    now: u64,
+) -> Result<Value, ErrorObject> {
+    let params: SignParams = params.parse(&["key", "digest", "api_key", "hash_algorithm"])?;
+    let hash_algo = params.hash_algorithm;
+    let (caller, key) = match params.key {
+        RpcKeyDescriptor::Local(key_ref) => {
+            let (caller, key_id) =
+                authorize_apikey(&params.api_key, "sign", &key_ref, Some(hash_algo), now)?;
+            (caller, KeyDescriptor::Local(key_id))
+        }
+        RpcKeyDescriptor::Threshold(sub_path) => {
+            let caller = authorize_threshold_apikey(&params.api_key, "sign", hash_algo, now)?;
+            let sub_path = sub_path
+                .iter()
+                .map(|element| hexstr_to_vec(element))
+                .collect::<Result<_, _>>()?;
+            (caller, KeyDescriptor::Threshold(sub_path))
+        }
+    };
+    let msg_hash = hexstr_to_vec(&params.digest)?;
+    let bundle = sign_with_key(mgmt, &caller, key, &msg_hash, hash_algo).await?;
+    Ok(Value::String(params.output.encode(bundle)?))
+}
+
+#[derive(serde::Deserialize)]
+struct GeneratePrivkeyParams {
+    api_key: String,
+    alias: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl MethodParams for GeneratePrivkeyParams {
+    const FIELDS: &'static [&'static str] = &["api_key", "alias", "description", "tags"];
+}
+
+// Positional params are `[api_key, alias, description, tags]`
+async fn rpc_generate_privkey(
+    mgmt: &impl ManagementCanister,
+    params: &Params,
+    now: u64,
+) -> Result<Value, ErrorObject> {
+    let params: GeneratePrivkeyParams =
+        params.parse(&["api_key", "alias", "description", "tags"])?;
+    let (caller, _) = authenticate_apikey(&params.api_key, "generate_privkey", now)?;
+    let args = PrivkeyGenArgs {
+        alias: params.alias,
+        description: params.description,
+        tags: params.tags,
+    };
+    let res = create_privkey(mgmt, &caller, args, now).await?;
+    Ok(serde_json::json!({
+        "key_id": res.key_id,
+        "publickey": vec8_to_hexstr(&res.publickey),
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct RotateApiKeyParams {
+    api_key: String,
+}
+
+impl MethodParams for RotateApiKeyParams {
+    const FIELDS: &'static [&'static str] = &["api_key"];
+}
+
+// Replaces the API key with a new one of the same name, scope and expiry
+async fn rpc_rotate_apikey(
+    mgmt: &impl ManagementCanister,
+    params: &Params,
+    now: u64,
+) -> Result<Value, ErrorObject> {
+    let params: RotateApiKeyParams = params.parse(&["api_key"])?;
+    authenticate_apikey(&params.api_key, "rotate_apikey", now)?;
+    let random = get_random(mgmt).await?;
+    // The old key may have been revoked or rotated while waiting for the randomness
+    let (_, info) = State::rotate_apikey(&params.api_key, &random, now)?;
+    Ok(serde_json::json!({
+        "name": info.name,
+        "api_key": random,
+    }))
+}
+
+// Finds the owner of an API key and checks the key may be used for `method`
+fn authenticate_apikey(
+    api_key: &str,
+    method: &str,
+    now: u64,
+) -> Result<(Principal, ApiKeyInfo), SignerError> {
+    let (caller, info) = State::get_caller_by_apikey(api_key).ok_or(SignerError::ApiKeyNotFound)?;
+    if info.is_expired(now) {
+        return Err(SignerError::ApiKeyExpired);
+    }
+    if !info.scope.allows_method(method) {
+        return Err(SignerError::OutOfApiKeyScope("method".to_string()));
     }
+    Ok((caller, info))
+}
 
-    pub fn set_apikey(principal: &Principal, key: &String) {
-        STATE.with(|state| {
-            let mut state = state.borrow_mut();
-            let _ = state.apiKeys.insert(*principal, key.clone());
-        })
+// Also checks the API key may use `key_ref`, and sign with `hash_algo` unless it's `None`
+fn authorize_apikey(
+    api_key: &str,
+    method: &str,
+    key_ref: &str,
+    hash_algo: Option<HashAlgorithm>,
+    now: u64,
+) -> Result<(Principal, String), SignerError> {
+    let (caller, info) = authenticate_apikey(api_key, method, now)?;
+    if let Some(hash_algo) = hash_algo {
+        authorize_hash_algorithm(&info, hash_algo)?;
+    }
+    let key_id = State::resolve_key_id(&caller, key_ref)?;
+    if !info.scope.allows_key(&key_id) {
+        return Err(SignerError::OutOfApiKeyScope("key".to_string()));
     }
+    Ok((caller, key_id))
+}
 
-    pub fn next_key_id(principal: &Principal) -> String {
-        STATE.with(|state| {
-            let state = state.borrow();
-            match state.privkeys.get(principal) {
-                Some(pk_map) => pk_map.len().to_string(),
-                None => String::from("0"),
-            }
-        })
+// Also checks the API key may sign with threshold keys and with `hash_algo`
+fn authorize_threshold_apikey(
+    api_key: &str,
+    method: &str,
+    hash_algo: HashAlgorithm,
+    now: u64,
+) -> Result<Principal, SignerError> {
+    let (caller, info) = authenticate_apikey(api_key, method, now)?;
+    if !info.scope.allows_threshold_keys() {
+        return Err(SignerError::OutOfApiKeyScope("key".to_string()));
     }
+    authorize_hash_algorithm(&info, hash_algo)?;
+    Ok(caller)
+}
 
-    pub fn get_privkey(principal: &Principal, key_id: &str) -> Result<String, String> {
-        STATE.with(|state| {
-            let state = state.borrow();
-            match state.privkeys.get(principal) {
-                Some(pk_map) => match pk_map.get(key_id) {
-                    Some(pk) => Ok(pk.clone()),
-                    None => Err("Key ID not found".to_string()),
-                },
-                None => Err("Principal not found".to_string()),
-            }
-        })
-    }
-
-    pub fn set_privkey(principal: &Principal, key_id: &String, key: &String) -> Result<(), String> {
-        STATE.with(|state| {
-            let mut state = state.borrow_mut();
-            match state.privkeys.get_mut(principal) {
-                Some(pk_map) => match pk_map.get(key_id) {
-                    Some(_) => return Err("This key ID is already exist".to_string()),
-                    None => {
-                        let _ = pk_map.insert(key_id.clone(), key.clone());
-                        return Ok(());
-                    }
-                },
-                None => {
-                    let mut new_pk_map = BTreeMap::new();
-                    new_pk_map.insert(String::from(key_id), String::from(key));
-                    state.privkeys.insert(*principal, new_pk_map);
-                    return Ok(());
-                }
-            };
-        })
+// Checks the API key may sign digests computed with `hash_algo`, on every signing path
+fn authorize_hash_algorithm(
+    info: &ApiKeyInfo,
+    hash_algo: HashAlgorithm,
+) -> Result<(), SignerError> {
+    if !info.scope.allows_hash_algorithm(hash_algo) {
+        return Err(SignerError::OutOfApiKeyScope("hash algorithm".to_string()));
     }
+    Ok(())
 }
 
+#[derive(Clone, CandidType, Deserialize, Default)]
+struct ApiKeyGenArgs {
+    name: Option<String>,
+    scope: Option<ApiKeyScope>,
+    /// Nanoseconds since the UNIX epoch
+    expires_at: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct ApiKeyGenRes {
+    name: String,
+    api_key: String,
+}
+
+// The API key itself is only returned here, it can't be queried afterwards
 #[ic_cdk_macros::update]
-async fn generate_apikey() -> String {
-    let caller = api::caller();
-    let random = match get_random().await {
-        Ok(rnd) => rnd,
-        Err(e) => return format!("Failed to generate a new key: {}", e),
+async fn generate_apikey(args: Option<ApiKeyGenArgs>) -> Result<ApiKeyGenRes, SignerError> {
+    create_apikey(&Ic00, &api::caller(), args.unwrap_or_default(), api::time()).await
+}
+
+async fn create_apikey(
+    mgmt: &impl ManagementCanister,
+    caller: &Principal,
+    args: ApiKeyGenArgs,
+    now: u64,
+) -> Result<ApiKeyGenRes, SignerError> {
+    reject_anonymous(caller)?;
+    if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(SignerError::ApiKeyExpired);
+    }
+    ensure_apikey_salt(mgmt).await?;
+    let random = get_random(mgmt).await?;
+    let info = ApiKeyInfo {
+        name: args.name.unwrap_or_else(|| State::next_apikey_name(caller)),
+        scope: args.scope.unwrap_or_default(),
+        created_at: now,
+        expires_at: args.expires_at,
     };
-    State::set_apikey(&caller, &random);
-    random
+    State::add_apikey(caller, &info, &random)?;
+    Ok(ApiKeyGenRes {
+        name: info.name,
+        api_key: random,
+    })
+}
+
+#[ic_cdk_macros::query]
+fn list_apikeys() -> Vec<ApiKeyInfo> {
+    State::list_apikeys(&api::caller())
+}
+
+#[ic_cdk_macros::update]
+fn revoke_apikey(name: String) -> Result<(), SignerError> {
+    State::revoke_apikey(&api::caller(), &name)
+}
+
+#[derive(Clone, CandidType, Deserialize, Default)]
+struct PrivkeyGenArgs {
+    alias: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
-struct PrivkeyGenRes(String, String);
+struct PrivkeyGenRes {
+    key_id: String,
+    publickey: Vec<u8>,
+}
 
 #[ic_cdk_macros::update]
-async fn generate_privkey() -> PrivkeyGenRes {
-    let caller = api::caller();
-    let key_id = State::next_key_id(&caller);
-    let random = match get_random().await {
-        Ok(rnd) => rnd,
-        Err(e) => {
-            return PrivkeyGenRes(
-                format!("Failed to generate a new key: {}", e),
-                String::from(""),
-            )
-        }
-    };
+async fn generate_privkey(args: Option<PrivkeyGenArgs>) -> Result<PrivkeyGenRes, SignerError> {
+    create_privkey(&Ic00, &api::caller(), args.unwrap_or_default(), api::time()).await
+}
+
+async fn create_privkey(
+    mgmt: &impl ManagementCanister,
+    caller: &Principal,
+    args: PrivkeyGenArgs,
+    now: u64,
+) -> Result<PrivkeyGenRes, SignerError> {
+    reject_anonymous(caller)?;
+    ensure_kek(mgmt).await?;
+    let random = get_random(mgmt).await?;
     let key = ECDSAPrivateKey::generate(&random);
-    let id = match State::set_privkey(&caller, &key_id, &key.to_string()) {
-        Ok(_) => key_id,
-        Err(e) => {
-            return PrivkeyGenRes(
-                format!("Failed to generate a new key: {}", e),
-                String::from(""),
-            )
-        }
+    let publickey = key.to_pubkey()?;
+    let info = KeyInfo {
+        key_id: State::allocate_key_id(caller),
+        publickey,
+        created_at: now,
+        curve: Curve::Secp256k1,
+        hash_algorithms: HashAlgorithm::all(),
+        status: KeyStatus::Active,
+        alias: args.alias,
+        description: args.description,
+        tags: args.tags,
+        migration: None,
     };
-    let pubkey = key.to_pubkey().unwrap();
-    PrivkeyGenRes(id, vec8_to_hexstr(&pubkey))
+    State::set_privkey(caller, &info, &key.to_string())?;
+    Ok(PrivkeyGenRes {
+        key_id: info.key_id,
+        publickey: info.publickey,
+    })
 }
 
-async fn get_random() -> Result<String, String> {
-    let ic00 = ic_cdk::export::Principal::management_canister();
-    let (rnd_buf,): (Vec<u8>,) = match ic_cdk::call(ic00, "raw_rand", ()).await {
-        Ok(res) => res,
-        Err(e) => return Err(format!("Failed to get random: {:?}", e)),
-    };
-    Ok(vec8_to_hexstr(&rnd_buf))
+#[ic_cdk_macros::query]
+fn list_keys() -> Vec<KeyInfo> {
+    State::list_keys(&api::caller())
+}
+
+#[ic_cdk_macros::query]
+fn find_keys_by_tag(tag: String) -> Vec<KeyInfo> {
+    State::find_keys_by_tag(&api::caller(), &tag)
+}
+
+#[ic_cdk_macros::query]
+fn describe_key(key_id: String) -> Result<KeyInfo, SignerError> {
+    State::get_key_info(&api::caller(), &key_id)
+}
+
+// `key_id` may also be the alias of the key, the key is uncompressed SEC1 by default
+#[ic_cdk_macros::query]
+fn get_public_key(
+    key_id: String,
+    encoding: Option<PublicKeyEncoding>,
+) -> Result<EncodedPublicKey, SignerError> {
+    let caller = api::caller();
+    let key_id = State::resolve_key_id(&caller, &key_id)?;
+    let info = State::get_key_info(&caller, &key_id)?;
+    types::ECDSAPublicKey::from_vec8(&info.publickey)?.encode(encoding.unwrap_or_default())
+}
+
+// `key_id` may also be the alias of the key
+#[ic_cdk_macros::query]
+fn get_address(key_id: String, address_type: AddressType) -> Result<String, SignerError> {
+    let caller = api::caller();
+    let key_id = State::resolve_key_id(&caller, &key_id)?;
+    let info = State::get_key_info(&caller, &key_id)?;
+    derive_address(&info.publickey, &address_type)
+}
+
+#[ic_cdk_macros::update]
+fn disable_key(key_id: String) -> Result<(), SignerError> {
+    State::set_key_enabled(&api::caller(), &key_id, false)
+}
+
+#[ic_cdk_macros::update]
+fn enable_key(key_id: String) -> Result<(), SignerError> {
+    State::set_key_enabled(&api::caller(), &key_id, true)
+}
+
+// Destroys the private key, its public key and metadata stay listed
+#[ic_cdk_macros::update]
+fn archive_key(key_id: String) -> Result<(), SignerError> {
+    State::archive_key(&api::caller(), &key_id)
+}
+
+#[ic_cdk_macros::update]
+fn delete_key(key_id: String) -> Result<(), SignerError> {
+    State::delete_key(&api::caller(), &key_id)
+}
+
+// Generates a fresh key-encryption key and re-seals a first batch of the stored private
+// keys under it, the owner re-seals the rest with `reseal_batch`
+#[ic_cdk_macros::update]
+async fn rotate_kek() -> Result<u32, SignerError> {
+    if State::get_owner() != Some(api::caller()) {
+        return Err(SignerError::Unauthorized);
+    }
+    let random = Ic00.raw_rand().await?;
+    let version = State::rotate_kek(&random)?;
+    State::reseal_privkeys(RESEAL_BATCH_SIZE)?;
+    Ok(version)
+}
+
+// Re-seals the next batch of private keys, returns true once they are all sealed under
+// the current key-encryption key
+#[ic_cdk_macros::update]
+async fn reseal_batch() -> Result<bool, SignerError> {
+    if State::get_owner() != Some(api::caller()) {
+        return Err(SignerError::Unauthorized);
+    }
+    reseal_privkeys(&Ic00).await
+}
+
+// Private keys re-sealed per call, so a large key store doesn't exceed the
+// instruction limit
+const RESEAL_BATCH_SIZE: usize = 100;
+
+// Private keys imported in plaintext from older releases may be waiting for the
+// first key-encryption key
+async fn reseal_privkeys(mgmt: &impl ManagementCanister) -> Result<bool, SignerError> {
+    if !State::reseal_pending() {
+        return Ok(true);
+    }
+    ensure_kek(mgmt).await?;
+    State::reseal_privkeys(RESEAL_BATCH_SIZE)
+}
+
+#[ic_cdk_macros::query]
+fn raw_privkey_enabled() -> bool {
+    State::raw_privkey_enabled()
+}
+
+#[ic_cdk_macros::update]
+fn set_raw_privkey_enabled(enabled: bool) -> Result<(), SignerError> {
+    if State::get_owner() != Some(api::caller()) {
+        return Err(SignerError::Unauthorized);
+    }
+    State::set_raw_privkey_enabled(enabled);
+    Ok(())
+}
+
+#[ic_cdk_macros::query]
+fn ecdsa_key_name() -> String {
+    State::ecdsa_key_name()
+}
+
+#[ic_cdk_macros::update]
+fn set_ecdsa_key_name(name: String) -> Result<(), SignerError> {
+    if State::get_owner() != Some(api::caller()) {
+        return Err(SignerError::Unauthorized);
+    }
+    State::set_ecdsa_key_name(&name)
+}
+
+async fn ensure_kek(mgmt: &impl ManagementCanister) -> Result<(), SignerError> {
+    if State::has_kek() {
+        return Ok(());
+    }
+    let random = mgmt.raw_rand().await?;
+    // Another call may have generated one while waiting for the randomness
+    if !State::has_kek() {
+        State::rotate_kek(&random)?;
+    }
+    Ok(())
+}
+
+async fn ensure_apikey_salt(mgmt: &impl ManagementCanister) -> Result<(), SignerError> {
+    if State::has_apikey_salt() {
+        return Ok(());
+    }
+    let random = mgmt.raw_rand().await?;
+    State::init_apikey_salt(&random)
+}
+
+async fn get_random(mgmt: &impl ManagementCanister) -> Result<String, SignerError> {
+    Ok(vec8_to_hexstr(&mgmt.raw_rand().await?))
 }
 
 // #[ic_cdk_macros::update]
@@ -310,209 +980,1473 @@ async fn get_random() -> Result<String, String> {
 //     key
 // }
 
-// #[ic_cdk_macros::query]
-// fn show_apikey() -> String {
-//     let caller = api::caller();
-//     let key = State::get_apikey(&caller).unwrap();
-//     key
-// }
+// `key_id` may also be the alias of the key, `hash_algo` defaults to Keccak256 and
+// `signature_format` to r||s||v
+#[ic_cdk_macros::query]
+fn sign_digest_mpc(
+    digest: String,
+    key_id: String,
+    hash_algo: Option<HashAlgorithm>,
+    signature_format: Option<SignatureFormat>,
+) -> Result<Bundle, SignerError> {
+    let caller = api::caller();
+    let hash_algo = hash_algo.unwrap_or_default();
+    let key_id = State::resolve_key_id(&caller, &key_id)?;
+    let key = State::get_privkey(&caller, &key_id, hash_algo)?;
+    sign_digest(&digest, &key, hash_algo)?.with_format(signature_format.unwrap_or_default())
+}
+
+// `message` is hex or base64 as told by `encoding`, hex by default
+#[ic_cdk_macros::query(name = "sign_message")]
+fn sign_message_mpc(
+    message: String,
+    key_id: String,
+    encoding: Option<MessageEncoding>,
+    hash_algo: Option<HashAlgorithm>,
+    signature_format: Option<SignatureFormat>,
+) -> Result<Bundle, SignerError> {
+    let caller = api::caller();
+    let hash_algo = hash_algo.unwrap_or_default();
+    let message = encoding.unwrap_or_default().decode(&message)?;
+    let key_id = State::resolve_key_id(&caller, &key_id)?;
+    let key = State::get_privkey(&caller, &key_id, hash_algo)?;
+    sign_message(&message, &key, hash_algo)?.with_format(signature_format.unwrap_or_default())
+}
 
+#[derive(Clone, CandidType, Deserialize)]
+struct VerifyArgs {
+    /// Hex, in place of `message`
+    digest: Option<String>,
+    /// Hashed with `hash_algorithm`, hex or base64 as told by `encoding`
+    message: Option<String>,
+    encoding: Option<MessageEncoding>,
+    /// r||s, r||s||v or ASN.1 DER, hex or base64 as told by `signature_encoding`
+    signature: String,
+    signature_encoding: Option<MessageEncoding>,
+    /// SEC1, hex, in place of `key_id`
+    publickey: Option<String>,
+    /// The key ID or alias of a key of the caller
+    key_id: Option<String>,
+    hash_algorithm: Option<HashAlgorithm>,
+}
+
+// Malformed input is reported in the reason rather than as an error
 #[ic_cdk_macros::query]
-fn sign_digest_mpc(digest: String, key_id: String) -> String {
+fn verify_signature(args: VerifyArgs) -> Verification {
     let caller = api::caller();
-    let key = State::get_privkey(&caller, &key_id).unwrap();
-    let res = sign_digest(&digest, &key);
-    match res {
-        Ok(res) => serde_json::to_string(&res).unwrap(),
-        Err(err) => format!("{{\"result\":\"{}\"}}\n", err).to_string(),
-    }
+    let publickey = match (&args.publickey, &args.key_id) {
+        (Some(publickey), None) => hexstr_to_vec(publickey),
+        (None, Some(key_ref)) => State::resolve_key_id(&caller, key_ref)
+            .and_then(|key_id| State::get_key_info(&caller, &key_id))
+            .map(|info| info.publickey),
+        _ => {
+            return Err("expected either `publickey` or `key_id`".to_string()).into();
+        }
+    };
+    verify(&args, publickey)
+}
+
+fn verify(args: &VerifyArgs, publickey: Result<Vec<u8>, SignerError>) -> Verification {
+    let check = || {
+        let publickey = publickey.map_err(|e| format!("public key: {}", e))?;
+        let hash_algo = args.hash_algorithm.unwrap_or_default();
+        let digest = match (&args.digest, &args.message) {
+            (Some(digest), None) => hexstr_to_vec(digest).map_err(|e| format!("digest: {}", e))?,
+            (None, Some(message)) => {
+                let message = args
+                    .encoding
+                    .unwrap_or_default()
+                    .decode(message)
+                    .map_err(|e| format!("message: {}", e))?;
+                hash_algo.hash(&message)
+            }
+            _ => return Err("expected either `digest` or `message`".to_string()),
+        };
+        let signature = args
+            .signature_encoding
+            .unwrap_or_default()
+            .decode(&args.signature)
+            .map_err(|e| format!("signature: {}", e))?;
+        check_signature(&digest, &signature, &publickey, hash_algo)
+    };
+    check().into()
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+struct RecoveredKey {
+    /// Uncompressed SEC1
+    publickey: Vec<u8>,
+    eth_address: String,
 }
 
-fn sign_digest(digest: &str, private_key: &str) -> Result<Bundle, String> {
-    let privkey = ECDSAPrivateKey::from_string(private_key)?;
-    let hash_algo = HashAlgorithm::Keccak256;
+// `signature` is r||s||v in hex, v may be 0/1 or 27/28
+#[ic_cdk_macros::query]
+fn recover_public_key(digest: String, signature: String) -> Result<RecoveredKey, SignerError> {
+    let msg_hash = hexstr_to_vec(&digest)?;
+    let sig = hexstr_to_vec(&signature)?;
+    let publickey = recover_pubkey(&msg_hash, &sig)?;
+    Ok(RecoveredKey {
+        eth_address: eth_address(&publickey)?,
+        publickey,
+    })
+}
 
+fn sign_digest(
+    digest: &str,
+    privkey: &ECDSAPrivateKey,
+    hash_algo: HashAlgorithm,
+) -> Result<Bundle, SignerError> {
     let msg_hash = hexstr_to_vec(digest)?;
 
     let sig = privkey.sign(&msg_hash, hash_algo)?;
-    let pubkey = privkey.to_pubkey()?;
-
-    let verified = verify_signature(&msg_hash, &sig, &pubkey);
-    if verified {
-        Ok(Bundle {
-            digest: msg_hash,
-            publickey: pubkey,
-            signature: sig,
-        })
-    } else {
-        return Err("Signature verified failed".to_string());
-    }
+    signature_bundle(msg_hash, sig, &privkey.to_pubkey()?, hash_algo)
 }
 
-type CanisterId = Principal;
-
-#[derive(CandidType, Serialize, Debug)]
-struct ECDSAPublicKey {
-    pub canister_id: Option<CanisterId>,
-    pub derivation_path: Vec<Vec<u8>>,
-    pub key_id: EcdsaKeyId,
+fn sign_message(
+    message: &[u8],
+    privkey: &ECDSAPrivateKey,
+    hash_algo: HashAlgorithm,
+) -> Result<Bundle, SignerError> {
+    let (msg_hash, sig) = privkey.sign_message(message, hash_algo)?;
+    signature_bundle(msg_hash, sig, &privkey.to_pubkey()?, hash_algo)
 }
 
-#[derive(CandidType, SerdeDeserialize, Debug)]
-struct ECDSAPublicKeyReply {
-    pub public_key: Vec<u8>,
-    pub chain_code: Vec<u8>,
+// The management canister accepts at most 255 elements, the first is the caller
+const MAX_DERIVATION_SUB_PATH_LEN: usize = 254;
+
+// The digest is signed as is, `hash_algo` is only recorded in the bundle. `signature_format`
+// defaults to r||s, as returned by the management canister, for backward compatibility:
+// `sign` with a `Threshold` key defaults to r||s||v like every other signing method.
+// `derivation_path` picks one of the caller's keys, the empty path by default
+#[ic_cdk_macros::update]
+async fn sign_digest_ic(
+    digest: String,
+    hash_algo: Option<HashAlgorithm>,
+    signature_format: Option<SignatureFormat>,
+    derivation_path: Option<Vec<Vec<u8>>>,
+) -> Result<Bundle, SignerError> {
+    let derivation_path =
+        caller_derivation_path(&api::caller(), derivation_path.unwrap_or_default())?;
+    sign_with_threshold_key(&Ic00, &digest, hash_algo, signature_format, derivation_path).await
 }
 
-#[derive(CandidType, Serialize, Debug)]
-struct SignWithECDSA {
-    pub message_hash: Vec<u8>,
-    pub derivation_path: Vec<Vec<u8>>,
-    pub key_id: EcdsaKeyId,
+// The key `sign_digest_ic` signs with for the same `derivation_path`
+#[ic_cdk_macros::update]
+async fn get_ic_public_key(
+    derivation_path: Option<Vec<Vec<u8>>>,
+) -> Result<IcPublicKey, SignerError> {
+    let derivation_path =
+        caller_derivation_path(&api::caller(), derivation_path.unwrap_or_default())?;
+    ThresholdKey::new(&Ic00, derivation_path)
+        .ic_public_key()
+        .await
 }
 
-#[derive(CandidType, SerdeDeserialize, Debug)]
-struct SignWithECDSAReply {
-    pub signature: Vec<u8>,
+// Anyone can call as the anonymous principal, so it can't own keys or API keys
+fn reject_anonymous(caller: &Principal) -> Result<(), SignerError> {
+    if *caller == Principal::anonymous() {
+        return Err(SignerError::Unauthorized);
+    }
+    Ok(())
 }
 
-#[derive(CandidType, Serialize, Debug, Clone)]
-struct EcdsaKeyId {
-    pub curve: EcdsaCurve,
-    pub name: String,
+// Keys are derived under the principal of the caller, so no two principals share one
+fn caller_derivation_path(
+    caller: &Principal,
+    sub_path: Vec<Vec<u8>>,
+) -> Result<Vec<Vec<u8>>, SignerError> {
+    reject_anonymous(caller)?;
+    if sub_path.len() > MAX_DERIVATION_SUB_PATH_LEN {
+        return Err(SignerError::InvalidArgument(format!(
+            "derivation path has more than {} elements",
+            MAX_DERIVATION_SUB_PATH_LEN
+        )));
+    }
+    Ok([vec![caller.as_slice().to_vec()], sub_path].concat())
 }
 
-#[derive(CandidType, Serialize, Debug, Clone)]
-pub enum EcdsaCurve {
-    #[serde(rename = "secp256k1")]
-    Secp256k1,
+async fn sign_with_threshold_key(
+    mgmt: &impl ManagementCanister,
+    digest: &str,
+    hash_algo: Option<HashAlgorithm>,
+    signature_format: Option<SignatureFormat>,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Bundle, SignerError> {
+    let msg_hash = hexstr_to_vec(digest)?;
+    ThresholdKey::new(mgmt, derivation_path)
+        .sign(&msg_hash, hash_algo.unwrap_or_default())
+        .await?
+        .with_format(signature_format.unwrap_or(SignatureFormat::Compact))
 }
 
+// Signs with a stored or a threshold key alike, `signature_format` defaults to r||s||v
 #[ic_cdk_macros::update]
-async fn sign_digest_ic(digest: String) -> String {
-    let msg_hash = match hexstr_to_vec(&digest) {
-        Ok(hash) => hash,
-        Err(_) => {
-            return format!("{{\"result\":\"Sign failed when decoding digest\"}}\n").to_string()
+async fn sign(
+    key: KeyDescriptor,
+    digest: String,
+    hash_algo: Option<HashAlgorithm>,
+    signature_format: Option<SignatureFormat>,
+) -> Result<Bundle, SignerError> {
+    let msg_hash = hexstr_to_vec(&digest)?;
+    sign_with_key(
+        &Ic00,
+        &api::caller(),
+        key,
+        &msg_hash,
+        hash_algo.unwrap_or_default(),
+    )
+    .await?
+    .with_format(signature_format.unwrap_or_default())
+}
+
+async fn sign_with_key(
+    mgmt: &impl ManagementCanister,
+    caller: &Principal,
+    key: KeyDescriptor,
+    msg_hash: &[u8],
+    hash_algo: HashAlgorithm,
+) -> Result<Bundle, SignerError> {
+    match key {
+        KeyDescriptor::Local(key_ref) => {
+            let key_id = State::resolve_key_id(caller, &key_ref)?;
+            LocalKey::new(State::get_privkey(caller, &key_id, hash_algo)?)
+                .sign(msg_hash, hash_algo)
+                .await
         }
+        KeyDescriptor::Threshold(sub_path) => {
+            ThresholdKey::new(mgmt, caller_derivation_path(caller, sub_path)?)
+                .sign(msg_hash, hash_algo)
+                .await
+        }
+    }
+}
+
+// How long a migrated local key keeps signing before it may be destroyed
+const KEY_MIGRATION_GRACE_PERIOD: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Debug)]
+struct KeyMigrationRes {
+    migration: KeyMigration,
+    /// The statement linking the keys, signed by both
+    attestation: String,
+    local_signature: Bundle,
+    threshold_signature: Bundle,
+}
+
+// Moves a local key to a threshold ECDSA key of the caller: both keys sign an attestation
+// linking them, and the local key is deprecated until `finish_key_migration` destroys it
+#[ic_cdk_macros::update]
+async fn migrate_key(key_id: String) -> Result<KeyMigrationRes, SignerError> {
+    let caller = api::caller();
+    let key_id = State::resolve_key_id(&caller, &key_id)?;
+    let sub_path = migration_sub_path(&key_id);
+    let target = ThresholdKey::new(&Ic00, caller_derivation_path(&caller, sub_path.clone())?);
+    migrate_local_key(&caller, &key_id, sub_path, &target, api::time()).await
+}
+
+// Destroys the private key of a migrated key once the grace period is over
+#[ic_cdk_macros::update]
+fn finish_key_migration(key_id: String) -> Result<(), SignerError> {
+    let caller = api::caller();
+    let key_id = State::resolve_key_id(&caller, &key_id)?;
+    State::destroy_migrated_key(&caller, &key_id, api::time())
+}
+
+// Each local key gets a threshold key of its own, apart from those chosen by the caller
+fn migration_sub_path(key_id: &str) -> Vec<Vec<u8>> {
+    vec![b"migrated".to_vec(), key_id.as_bytes().to_vec()]
+}
+
+fn migration_attestation(
+    owner: &Principal,
+    key_id: &str,
+    local_publickey: &[u8],
+    threshold_publickey: &[u8],
+    deprecated_at: u64,
+) -> String {
+    format!(
+        "ic_signer key migration\nowner: {}\nkey_id: {}\nfrom: {}\nto: {}\nat: {}",
+        owner.to_text(),
+        key_id,
+        hex::encode(local_publickey),
+        hex::encode(threshold_publickey),
+        deprecated_at
+    )
+}
+
+// `target` is the threshold key at `sub_path`
+async fn migrate_local_key<B: SigningBackend>(
+    caller: &Principal,
+    key_id: &str,
+    sub_path: Vec<Vec<u8>>,
+    target: &B,
+    now: u64,
+) -> Result<KeyMigrationRes, SignerError> {
+    let info = State::get_key_info(caller, key_id)?;
+    if info.migration.is_some() {
+        return Err(SignerError::InvalidArgument(
+            "key is already migrated".to_string(),
+        ));
+    }
+    // The attestation is hashed with an algorithm the local key may sign
+    let hash_algo = if info.hash_algorithms.contains(&HashAlgorithm::default()) {
+        HashAlgorithm::default()
+    } else {
+        *info
+            .hash_algorithms
+            .first()
+            .ok_or(SignerError::HashAlgorithmNotAllowed)?
     };
-    assert!(msg_hash.len() == 32);
-
-    let ic00 = ic_cdk::export::Principal::management_canister();
-    // let (rnd_buf,): (Vec<u8>,) = match ic_cdk::call(ic00, "raw_rand", ()).await {
-    //     Ok(res) => res,
-    //     Err(_) => return "Sign failed when parsing request body".to_string(),
-    // };
-    // return vec8_to_hexstr(&rnd_buf);
-    // let ic00_canister_id = "aaaaa-aa".to_string();
-    let key_id = EcdsaKeyId {
-        curve: EcdsaCurve::Secp256k1,
-        name: "".to_string(),
+    let local = LocalKey::new(State::get_privkey(caller, key_id, hash_algo)?);
+    let threshold_publickey =
+        types::ECDSAPublicKey::from_vec8(&target.public_key().await?)?.to_vec8();
+    let attestation =
+        migration_attestation(caller, key_id, &info.publickey, &threshold_publickey, now);
+    let msg_hash = hash_algo.hash(attestation.as_bytes());
+    let local_signature = local.sign(&msg_hash, hash_algo).await?;
+    let threshold_signature = target.sign(&msg_hash, hash_algo).await?;
+    let migration = KeyMigration {
+        derivation_path: sub_path,
+        publickey: threshold_publickey,
+        deprecated_at: now,
+        destroy_after: now.saturating_add(KEY_MIGRATION_GRACE_PERIOD),
     };
-    // let this_canister_id = "ptf43-biaaa-aaaai-ack3q-cai".to_string();
-    // let this_canister = CanisterId::from_str(&this_canister_id).unwrap();
-    // let pubkey: Vec<u8> = {
-    //     let request = ECDSAPublicKey {
-    //         canister_id: None, // Some(this_canister),
-    //         derivation_path: vec![vec![2, 3]],
-    //         key_id: key_id.clone(),
-    //     };
-    //     // ic_cdk::println!("Sending signature request = {:?}", request);
-    //     let (res,): (ECDSAPublicKeyReply,) = ic_cdk::call(ic00, "ecdsa_public_key", (request,))
-    //         .await
-    //         .map_err(|e| format!("Failed to call ecdsa_public_key: {}", e.1))
-    //         .unwrap();
-    //     // ic_cdk::println!("Got response = {:?}", res);
-    //     res.public_key
-    // };
-
-    let sig: Vec<u8> = {
-        let request = SignWithECDSA {
-            message_hash: msg_hash.clone(),
-            derivation_path: vec![vec![2, 3]],
-            key_id,
+    // The key may have been migrated or archived while waiting for the signature
+    State::deprecate_key(caller, key_id, &migration)?;
+    Ok(KeyMigrationRes {
+        migration,
+        attestation,
+        local_signature,
+        threshold_signature,
+    })
+}
+
+#[cfg(test)]
+fn post_rpc(body: &str) -> (u16, Value) {
+    let response = serve_http_request(
+        HttpRequest {
+            url: "/?canisterId=rrkah-fqaaa-aaaaa-aaaaq-cai".to_string(),
+            method: "POST".to_string(),
+            body: Some(body.as_bytes().to_vec()),
+            headers: vec![],
+        },
+        0,
+    );
+    let reply = serde_json::from_slice(&response.body).unwrap();
+    (response.status_code, reply)
+}
+
+// Runs the calls of an HTTP update request against `mgmt`
+#[cfg(test)]
+fn post_rpc_update(mgmt: &MockManagementCanister, method: &str, params: Value, now: u64) -> Value {
+    let body = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+    let batch = Batch::parse(body.to_string().as_bytes()).ok().unwrap();
+    let mut results = Vec::new();
+    for (method, params) in batch.calls() {
+        results.push(backend::block_on(dispatch_rpc_update(
+            mgmt, method, params, now,
+        )));
+    }
+    serde_json::from_slice(&batch.reply(results).body).unwrap()
+}
+
+#[cfg(test)]
+fn parse_privkey(privkey: &str) -> ECDSAPrivateKey {
+    ECDSAPrivateKey::from_string(privkey).unwrap()
+}
+
+#[test]
+fn test_rpc_sign_digest() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let body = format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_digest","params":["{}","{}"],"id":15}}"#,
+        privkey, digest
+    );
+    let (status_code, reply) = post_rpc(&body);
+    assert_eq!(status_code, 403);
+    assert_eq!(
+        reply["error"]["message"],
+        SignerError::RawPrivkeyDisabled.to_string()
+    );
+
+    State::set_raw_privkey_enabled(true);
+    let (status_code, reply) = post_rpc(&body);
+    let signature = sign_digest(digest, &parse_privkey(privkey), HashAlgorithm::Keccak256)
+        .unwrap()
+        .signature;
+    assert_eq!(status_code, 200);
+    assert_eq!(reply["id"], 15);
+    assert_eq!(reply["result"], vec8_to_hexstr(&signature));
+
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_digest","params":{{"privkey":"{}","digest":"{}","key_id":"1"}},"id":16}}"#,
+        privkey, digest
+    ));
+    assert_eq!(status_code, 400);
+    assert_eq!(reply["error"]["code"], jsonrpc::INVALID_PARAMS);
+
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_digest","params":{{"privkey":"{}","digest":"{}","hash_algorithm":"SHA3_256"}},"id":17}}"#,
+        privkey, digest
+    ));
+    let bundle = sign_digest(digest, &parse_privkey(privkey), HashAlgorithm::SHA3_256).unwrap();
+    assert_eq!(status_code, 200);
+    assert_eq!(reply["result"], vec8_to_hexstr(&bundle.signature));
+    assert_eq!(bundle.hash_algorithm, HashAlgorithm::SHA3_256);
+}
+
+#[test]
+fn test_rpc_update() {
+    use serde_json::json;
+
+    let mgmt = MockManagementCanister::new(b"seed");
+    let alice = Principal::from_slice(&[1; 29]);
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let msg_hash = hexstr_to_vec(digest).unwrap();
+    let call = |method, params, now| post_rpc_update(&mgmt, method, params, now);
+    let generate_apikey = |scope, expires_at| {
+        let args = ApiKeyGenArgs {
+            name: None,
+            scope: Some(scope),
+            expires_at,
         };
-        // ic_cdk::println!("Sending signature request = {:?}", request);
-        let (res,): (SignWithECDSAReply,) = ic_cdk::call(ic00, "sign_with_ecdsa", (request,))
-            .await
-            .map_err(|e| format!("Failed to call sign_with_ecdsa {}", e.1))
-            .unwrap();
-        // ic_cdk::println!("Got response = {:?}", res);
-        res.signature
+        backend::block_on(create_apikey(&mgmt, &alice, args, 1000))
+            .unwrap()
+            .api_key
     };
+    let signature = |reply: &Value| hexstr_to_vec(reply["result"].as_str().unwrap()).unwrap();
+    let error = |reply: &Value, e: SignerError| reply["error"]["message"] == e.to_string();
 
-    let verified = true; // verify_signature(&msg_hash, &sig, &pubkey);
-    if verified {
-        let res = Bundle {
-            digest: msg_hash,
-            publickey: Vec::new(), // pubkey,
-            signature: sig,
-        };
-        return serde_json::to_string(&res).unwrap();
-    } else {
-        return format!("{{\"result\":\"Sign failed, signature verified failed\"}}\n").to_string();
+    let api_key = generate_apikey(ApiKeyScope::default(), Some(5000));
+    let reply = call(
+        "generate_privkey",
+        json!({"api_key": api_key, "alias": "main"}),
+        2000,
+    );
+    let key_id = reply["result"]["key_id"].as_str().unwrap().to_string();
+    let publickey = hexstr_to_vec(reply["result"]["publickey"].as_str().unwrap()).unwrap();
+    assert_eq!(
+        State::get_key_info(&alice, &key_id).unwrap().created_at,
+        2000
+    );
+
+    let reply = call("sign", json!([{"Local": "main"}, digest, api_key]), 2000);
+    assert_eq!(
+        recover_pubkey(&msg_hash, &signature(&reply)).unwrap(),
+        publickey
+    );
+
+    let threshold_key = ThresholdKey::new(
+        &mgmt,
+        caller_derivation_path(&alice, vec![vec![7]]).unwrap(),
+    );
+    let threshold_key = backend::block_on(threshold_key.public_key()).unwrap();
+    let verifies = |reply: &Value| {
+        utils::verify_signature(
+            &msg_hash,
+            &signature(reply),
+            &threshold_key,
+            HashAlgorithm::Keccak256,
+        )
+    };
+    assert!(verifies(&call(
+        "sign",
+        json!([{"Threshold": ["07"]}, digest, api_key]),
+        2000
+    )));
+    assert!(verifies(&call(
+        "sign_digest_ic",
+        json!([digest, api_key, null, ["07"]]),
+        2000
+    )));
+    for (format, len) in [
+        (json!(null), 64),
+        (json!("Recoverable"), 65),
+        (json!("Ethereum"), 65),
+        (json!({"EIP155": 1}), 65),
+        (json!("EIP2098"), 64),
+    ] {
+        let reply = call(
+            "sign_digest_ic",
+            json!({"digest": digest, "api_key": api_key, "derivation_path": ["07"],
+                "signature_format": format}),
+            2000,
+        );
+        assert_eq!(signature(&reply).len(), len);
+        assert!(verifies(&reply));
     }
+    assert!(error(
+        &call("sign_digest_ic", json!([digest, api_key]), 5000),
+        SignerError::ApiKeyExpired
+    ));
+
+    // The scope of the API key applies to threshold keys too
+    let sha256_only = generate_apikey(
+        ApiKeyScope {
+            hash_algorithms: Some(vec![HashAlgorithm::SHA2_256]),
+            ..Default::default()
+        },
+        None,
+    );
+    let out_of_scope = |what: &str| SignerError::OutOfApiKeyScope(what.to_string());
+    assert!(error(
+        &call("sign_digest_ic", json!([digest, sha256_only]), 2000),
+        out_of_scope("hash algorithm")
+    ));
+    assert!(error(
+        &call(
+            "sign",
+            json!([{"Threshold": []}, digest, sha256_only]),
+            2000
+        ),
+        out_of_scope("hash algorithm")
+    ));
+    let reply = call(
+        "sign_digest_ic",
+        json!({"digest": digest, "api_key": sha256_only, "hash_algorithm": "SHA2_256"}),
+        2000,
+    );
+    assert!(reply["result"].is_string());
+    // A misspelled param is rejected rather than replaced by its default
+    let reply = call(
+        "sign_digest_ic",
+        json!({"digest": digest, "api_key": sha256_only, "hash_algo": "SHA2_256"}),
+        2000,
+    );
+    assert_eq!(reply["error"]["code"], jsonrpc::INVALID_PARAMS);
+    assert_eq!(reply["error"]["data"], "unknown param `hash_algo`");
+
+    let main_only = generate_apikey(
+        ApiKeyScope {
+            key_ids: Some(vec![key_id]),
+            ..Default::default()
+        },
+        None,
+    );
+    assert!(
+        call("sign", json!([{"Local": "main"}, digest, main_only]), 2000)["result"].is_string()
+    );
+    assert!(error(
+        &call("sign", json!([{"Threshold": []}, digest, main_only]), 2000),
+        out_of_scope("key")
+    ));
+    assert!(error(
+        &call("sign_digest_ic", json!([digest, main_only]), 2000),
+        out_of_scope("key")
+    ));
+
+    let reply = call("rotate_apikey", json!([api_key]), 3000);
+    let rotated = reply["result"]["api_key"].as_str().unwrap().to_string();
+    assert_eq!(reply["result"]["name"], "key-1");
+    assert!(error(
+        &call("sign_digest_ic", json!([digest, api_key]), 3000),
+        SignerError::ApiKeyNotFound
+    ));
+    assert!(verifies(&call(
+        "sign_digest_ic",
+        json!([digest, rotated, null, ["07"]]),
+        3000
+    )));
+    let (_, info) = State::get_caller_by_apikey(&rotated).unwrap();
+    assert_eq!((info.created_at, info.expires_at), (3000, Some(5000)));
+}
+
+#[test]
+fn test_init_args() {
+    let alice = Principal::from_slice(&[1; 29]);
+    State::set_owner(&Principal::from_slice(&[9; 10]));
+    let args = InitArgs {
+        owner: Some(alice),
+        ecdsa_key_name: Some("test_key_1".to_string()),
+        ..Default::default()
+    };
+    args.apply();
+    assert_eq!(State::get_owner(), Some(alice));
+    assert_eq!(State::ecdsa_key_name(), "test_key_1");
+    // Settings missing from the args are left as they were
+    InitArgs::default().apply();
+    assert_eq!(State::get_owner(), Some(alice));
+}
+
+#[test]
+fn test_rpc_errors() {
+    let (status_code, _) =
+        post_rpc(r#"{"jsonrpc":"2.0","method":"sign_digest","params":["1"],"id":1}"#);
+    assert_eq!(status_code, 400);
+    let (status_code, _) =
+        post_rpc(r#"{"jsonrpc":"2.0","method":"sign_digest","params":["a","00","b"],"id":1}"#);
+    assert_eq!(status_code, 401);
+    let (status_code, reply) = post_rpc(&"1".repeat(MAX_REQUEST_BODY_LEN + 1));
+    assert_eq!(status_code, 413);
+    assert_eq!(reply["error"]["code"], jsonrpc::INVALID_REQUEST);
+
+    let response = serve_http_request(
+        HttpRequest {
+            url: "/".to_string(),
+            method: "GET".to_string(),
+            body: None,
+            headers: vec![],
+        },
+        0,
+    );
+    assert_eq!(response.status_code, 404);
+    assert!(serde_json::from_slice::<Value>(&response.body).is_ok());
 }
 
 #[test]
-fn test_parse_request() {
-    let body = Some(
-        r#"{
-        "id":"15",
-        "method":"sign_digest",
-        "params":[
-            "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d",
-            "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2",
-            "6c9e1c6907e0173d9b59ab4cc2fda96283f83411b08798ae8d824969abcb8d56"
-        ]
-    }"#
-        .as_bytes()
-        .to_vec(),
-    );
-    let req = HttpRequest {
-        url: "/?canisterId=rrkah-fqaaa-aaaaa-aaaaq-cai".to_string(),
+fn test_rpc_upgrade() {
+    let request = |body: &str| HttpRequest {
+        url: "/".to_string(),
         method: "POST".to_string(),
-        body: body,
+        body: Some(body.as_bytes().to_vec()),
         headers: vec![],
     };
-
-    let res = parse_request(&req).unwrap();
-    println!("params: {:?}", res.params);
+    let response = serve_http_request(
+        request(r#"{"jsonrpc":"2.0","method":"sign_digest_ic","params":["00","key"],"id":1}"#),
+        0,
+    );
+    assert_eq!(response.upgrade, Some(true));
+    let response = serve_http_request(
+        request(
+            r#"[{"jsonrpc":"2.0","method":"sign_digest","params":["00","00"],"id":1},
+            {"jsonrpc":"2.0","method":"Generate_Privkey","params":["key"],"id":2}]"#,
+        ),
+        0,
+    );
+    assert_eq!(response.upgrade, Some(true));
+    let response = serve_http_request(
+        request(r#"{"jsonrpc":"2.0","method":"sign_digest","params":["00","00"],"id":1}"#),
+        0,
+    );
+    assert_eq!(response.upgrade, Some(false));
+    let response = serve_http_request(
+        request(r#"{"jsonrpc":"2.0","method":"sign","params":[{"Local":"1"},"00","key"],"id":1}"#),
+        0,
+    );
+    assert_eq!(response.upgrade, Some(true));
 }
 
 // dfx canister --network ic --wallet "$(dfx identity --network ic get-wallet)" update-settings --all --add-controller "$(dfx identity get-principal)"
 #[test]
 fn test_sign() {
-    let mut privkey_str = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
-    let mut privkey = ECDSAPrivateKey::from_string(privkey_str).unwrap();
-    println!("{}", privkey.to_string());
+    let privkey_str = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let privkey = ECDSAPrivateKey::from_string(privkey_str).unwrap();
+    assert_eq!(privkey.to_string(), privkey_str);
 
     let pk_vec = vec![
         0x3e, 0x3a, 0x84, 0xd1, 0x85, 0xa1, 0x1b, 0xe1, 0xda, 0xaf, 0xad, 0x1d, 0x01, 0xa7, 0xe1,
         0x5e, 0x04, 0x04, 0xab, 0x24, 0xed, 0x4b, 0x8d, 0xe5, 0x89, 0x71, 0xad, 0x93, 0x3e, 0x3f,
         0xc2, 0x4e,
     ];
-    privkey = ECDSAPrivateKey::from_vec8(&pk_vec).unwrap();
-    println!("{}", privkey.to_string());
-
-    privkey_str = "7009677dc021462d3db7ebc60077b6077f2b15837bf92b46ec5aa45afb820dbc";
-    privkey = ECDSAPrivateKey::from_string(privkey_str).unwrap();
-    println!("{}", privkey.to_string());
+    let privkey = ECDSAPrivateKey::from_vec8(&pk_vec).unwrap();
+    assert_eq!(privkey.to_string(), vec8_to_hexstr(&pk_vec));
 
     let msg = hexstr_to_vec("5fff1dae8dc8e2fc4d5b23b2c7665c97f9e9d8edf2b6485a86ba311c25639191b68878628428cccc90b0000000000100a6823403ea3055000000572d3ccdcd0150b64a9339aca0f100000000a8ed32322950b64a9339aca0f1102a8d6aaba430bde80300000000000004454f5300000000083130303130313033000000000000000000000000000000000000000000000000000000000000000000").unwrap();
     let msg_hash = hash_keccak256(&msg);
-    println!("msg_hash: {}", vec8_to_hexstr(&msg_hash));
-    // let msg_hash =
-    //     hexstr_to_vec("7d266152744bf8df4f7a2573d12856a635365fae4e74e19407fe3025a27a7733")?;
-
-    let message = "Hello world"; // 369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2
-    let message_u8 = hexstr_to_vec(&hex::encode(message)).unwrap();
-    let msg_hash = hash_sha256(&message_u8);
-    println!("msg_hash: {}", vec8_to_hexstr(&msg_hash));
-    let msg_hash = hash_keccak256(&message_u8);
-    println!("msg_hash: {}", vec8_to_hexstr(&msg_hash));
-
-    let sig_info = sign_digest(message, &privkey.to_string()).unwrap();
-    println!("signature: {}", vec8_to_hexstr(&sig_info.signature));
-    println!("pubkey: {}", vec8_to_hexstr(&sig_info.publickey));
+    let bundle = sign_digest(
+        &vec8_to_hexstr(&msg_hash),
+        &privkey,
+        HashAlgorithm::Keccak256,
+    )
+    .unwrap();
+    assert_eq!(
+        recover_pubkey(&msg_hash, &bundle.signature).unwrap(),
+        privkey.to_pubkey().unwrap()
+    );
+
+    let privkey_str = "7009677dc021462d3db7ebc60077b6077f2b15837bf92b46ec5aa45afb820dbc";
+    let publickey = ECDSAPrivateKey::from_string(privkey_str)
+        .unwrap()
+        .to_pubkey()
+        .unwrap();
+    let message = b"Hello world";
+    assert_eq!(
+        vec8_to_hexstr(&hash_sha3_256(message)),
+        "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2"
+    );
+    for hash_algo in [HashAlgorithm::Keccak256, HashAlgorithm::SHA3_256] {
+        let msg_hash = hash_algo.hash(message);
+        let bundle = sign_digest(
+            &vec8_to_hexstr(&msg_hash),
+            &parse_privkey(privkey_str),
+            hash_algo,
+        )
+        .unwrap();
+        assert_eq!(bundle.digest, msg_hash);
+        assert_eq!(bundle.publickey, publickey);
+        assert_eq!(bundle.hash_algorithm, hash_algo);
+        assert_eq!(bundle.signature.len(), 65);
+        assert_eq!(
+            recover_pubkey(&msg_hash, &bundle.signature).unwrap(),
+            publickey
+        );
+        assert!(utils::verify_signature(
+            &msg_hash,
+            &bundle.signature,
+            &publickey,
+            hash_algo
+        ));
+    }
+}
+
+#[test]
+fn test_sign_errors() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    assert_eq!(
+        sign_digest(
+            "Hello world",
+            &parse_privkey(privkey),
+            HashAlgorithm::Keccak256
+        )
+        .unwrap_err(),
+        SignerError::InvalidHex
+    );
+    assert!(matches!(
+        sign_digest("369183d3", &parse_privkey(privkey), HashAlgorithm::SHA3_256).unwrap_err(),
+        SignerError::InvalidLength(_)
+    ));
+    assert!(matches!(
+        ECDSAPrivateKey::from_string("6a73").unwrap_err(),
+        SignerError::InvalidLength(_)
+    ));
+}
+
+#[test]
+fn test_sign_message() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let message = b"Hello world".to_vec();
+    for hash_algo in HashAlgorithm::all() {
+        let bundle = sign_message(&message, &parse_privkey(privkey), hash_algo).unwrap();
+        let digest = hash_algo.hash(&message);
+        assert_eq!(bundle.digest, digest);
+        assert_eq!(bundle.hash_algorithm, hash_algo);
+        let expected =
+            sign_digest(&vec8_to_hexstr(&digest), &parse_privkey(privkey), hash_algo).unwrap();
+        assert_eq!(bundle.signature, expected.signature);
+    }
+
+    State::set_raw_privkey_enabled(true);
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_message","params":{{"privkey":"{}","message":"{}","encoding":"Base64"}},"id":1}}"#,
+        privkey,
+        base64::encode(&message)
+    ));
+    assert_eq!(status_code, 200);
+    assert_eq!(
+        reply["result"]["digest"],
+        vec8_to_hexstr(&hash_keccak256(&message))
+    );
+
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_message","params":["{}","not hex"],"id":2}}"#,
+        privkey
+    ));
+    assert_eq!(status_code, 400);
+    assert_eq!(
+        reply["error"]["message"],
+        SignerError::InvalidHex.to_string()
+    );
+}
+
+#[test]
+fn test_hash_algorithms() {
+    let data = b"abc";
+    let cases = [
+        (
+            HashAlgorithm::SHA2_256,
+            hash_sha256(data),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            HashAlgorithm::DoubleSHA2_256,
+            hash_double_sha256(data),
+            "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358",
+        ),
+        (
+            HashAlgorithm::Blake2b256,
+            hash_blake2b_256(data),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319",
+        ),
+        (
+            HashAlgorithm::SHA3_256,
+            hash_sha3_256(data),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532",
+        ),
+        (
+            HashAlgorithm::Keccak256,
+            hash_keccak256(data),
+            "4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45",
+        ),
+    ];
+    for (hash_algo, digest, expected) in cases {
+        assert_eq!(vec8_to_hexstr(&digest), expected);
+        assert_eq!(hash_algo.hash(data), digest);
+    }
+}
+
+#[test]
+fn test_verify_signature() {
+    use k256::ecdsa::{signature::Signature as _, Signature};
+
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let message = b"Hello world".to_vec();
+    let args = |bundle: &Bundle| VerifyArgs {
+        digest: Some(vec8_to_hexstr(&bundle.digest)),
+        message: None,
+        encoding: None,
+        signature: vec8_to_hexstr(&bundle.signature),
+        signature_encoding: None,
+        publickey: None,
+        key_id: None,
+        hash_algorithm: Some(bundle.hash_algorithm),
+    };
+    for hash_algo in HashAlgorithm::all() {
+        let bundle = sign_message(&message, &parse_privkey(privkey), hash_algo).unwrap();
+        let publickey = bundle.publickey.clone();
+        assert!(verify(&args(&bundle), Ok(publickey.clone())).valid);
+
+        let by_message = VerifyArgs {
+            digest: None,
+            message: Some(base64::encode(&message)),
+            encoding: Some(MessageEncoding::Base64),
+            ..args(&bundle)
+        };
+        assert!(verify(&by_message, Ok(publickey.clone())).valid);
+        let other_algo = HashAlgorithm::all()
+            .into_iter()
+            .find(|algo| *algo != hash_algo);
+        let verification = verify(
+            &VerifyArgs {
+                hash_algorithm: other_algo,
+                ..by_message
+            },
+            Ok(publickey.clone()),
+        );
+        assert!(!verification.valid);
+        assert!(verification.reason.is_some());
+
+        let signature = Signature::from_bytes(&bundle.signature[..64]).unwrap();
+        for sig in [
+            signature.as_ref().to_vec(),
+            signature.to_der().as_ref().to_vec(),
+        ] {
+            let verification = verify(
+                &VerifyArgs {
+                    signature: base64::encode(&sig),
+                    signature_encoding: Some(MessageEncoding::Base64),
+                    ..args(&bundle)
+                },
+                Ok(publickey.clone()),
+            );
+            assert_eq!(verification, Ok(()).into());
+        }
+    }
+
+    // Only the known layouts are taken, v must be a recovery id and s must be low
+    let bundle = sign_message(&message, &parse_privkey(privkey), HashAlgorithm::Keccak256).unwrap();
+    let check = |sig: &[u8]| {
+        check_signature(
+            &bundle.digest,
+            sig,
+            &bundle.publickey,
+            HashAlgorithm::Keccak256,
+        )
+    };
+    let rs = &bundle.signature[..64];
+    let with_v = |v: u8| [rs, &[v]].concat();
+    for v in [0, 1, 27, 28, 37, 38, 255] {
+        assert_eq!(check(&with_v(v)), Ok(()));
+    }
+    for v in [2, 26, 29, 34] {
+        assert_eq!(
+            check(&with_v(v)),
+            Err(format!("invalid recovery id {:02x}", v))
+        );
+    }
+    // EIP-155 with a large chain ID
+    assert_eq!(check(&[rs, &[1, 0, 0, 0, 0x25]].concat()), Ok(()));
+    for v in [[0, 0x25], [0, 27]] {
+        assert_eq!(
+            check(&[rs, &v].concat()),
+            Err(format!("invalid recovery id {}", hex::encode(v)))
+        );
+    }
+    for sig in [[rs, &[1; 9]].concat(), rs[..63].to_vec()] {
+        assert_eq!(
+            check(&sig),
+            Err(format!("malformed signature of {} bytes", sig.len()))
+        );
+    }
+
+    let signature = Signature::from_bytes(rs).unwrap();
+    let (r, s) = signature.split_scalars();
+    let high_s = Signature::from_scalars(r, -s).unwrap();
+    for sig in [
+        [high_s.as_ref(), &[0]].concat(),
+        high_s.to_der().as_bytes().to_vec(),
+    ] {
+        assert!(check(&sig).unwrap_err().contains("not canonical"));
+    }
+    // Taken as EIP-2098, which never has a high s
+    assert!(check(high_s.as_ref()).is_err());
+    // EIP-2098 keeps the y parity in the top bit of s
+    let mut compact = rs.to_vec();
+    compact[32] |= 0x80;
+    assert_eq!(check(&compact), Ok(()));
+
+    let verification = verify(
+        &VerifyArgs {
+            signature: "0011".to_string(),
+            ..args(&bundle)
+        },
+        Ok(bundle.publickey.clone()),
+    );
+    assert!(!verification.valid);
+    let verification = verify(&args(&bundle), Err(SignerError::KeyNotFound));
+    assert_eq!(
+        verification.reason.unwrap(),
+        format!("public key: {}", SignerError::KeyNotFound)
+    );
+    assert!(!verify(&args(&bundle), Ok(vec![4; 65])).valid);
+
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"verify_signature","params":["{}","{}","{}"],"id":1}}"#,
+        vec8_to_hexstr(&bundle.publickey),
+        vec8_to_hexstr(&bundle.digest),
+        vec8_to_hexstr(&bundle.signature)
+    ));
+    assert_eq!(status_code, 200);
+    assert_eq!(reply["result"]["valid"], true);
+
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"verify_signature","params":{{"publickey":"{}","digest":"00","signature":"{}"}},"id":2}}"#,
+        vec8_to_hexstr(&bundle.publickey),
+        vec8_to_hexstr(&bundle.signature)
+    ));
+    assert_eq!(status_code, 200);
+    assert_eq!(reply["result"]["valid"], false);
+    assert!(reply["result"]["reason"].is_string());
+
+    let (status_code, _) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"verify_signature","params":{{"key_id":"1","digest":"00","signature":"{}"}},"id":3}}"#,
+        vec8_to_hexstr(&bundle.signature)
+    ));
+    assert_eq!(status_code, 400);
+}
+
+#[test]
+fn test_recover_public_key() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let bundle = sign_digest(digest, &parse_privkey(privkey), HashAlgorithm::Keccak256).unwrap();
+    let mut signature = bundle.signature.clone();
+    let key = recover_public_key(digest.to_string(), vec8_to_hexstr(&signature)).unwrap();
+    assert_eq!(key.publickey, bundle.publickey);
+    signature[64] += 27;
+    let key = recover_public_key(digest.to_string(), vec8_to_hexstr(&signature)).unwrap();
+    assert_eq!(key.publickey, bundle.publickey);
+    assert_eq!(key.eth_address, eth_address(&bundle.publickey).unwrap());
+
+    signature[64] = 2;
+    assert!(recover_public_key(digest.to_string(), vec8_to_hexstr(&signature)).is_err());
+    assert!(matches!(
+        recover_public_key(digest.to_string(), hex::encode(&signature[..64])).unwrap_err(),
+        SignerError::InvalidLength(_)
+    ));
+
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"recover_public_key","params":["{}","{}"],"id":1}}"#,
+        digest,
+        vec8_to_hexstr(&bundle.signature)
+    ));
+    assert_eq!(status_code, 200);
+    assert_eq!(
+        reply["result"]["publickey"],
+        vec8_to_hexstr(&bundle.publickey)
+    );
+}
+
+#[test]
+fn test_make_recoverable() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let bundle = sign_digest(digest, &parse_privkey(privkey), HashAlgorithm::Keccak256).unwrap();
+    let msg_hash = hexstr_to_vec(digest).unwrap();
+    let rs = &bundle.signature[..64];
+    assert_eq!(
+        make_recoverable(&msg_hash, rs, &bundle.publickey).unwrap(),
+        bundle.signature
+    );
+    let other = sign_digest(
+        digest,
+        &parse_privkey(&"1".repeat(64)),
+        HashAlgorithm::Keccak256,
+    )
+    .unwrap();
+    assert!(matches!(
+        make_recoverable(&msg_hash, rs, &other.publickey),
+        Err(SignerError::VerificationFailed)
+    ));
+}
+
+#[test]
+fn test_signature_formats() {
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let sign = || sign_digest(digest, &parse_privkey(privkey), HashAlgorithm::Keccak256).unwrap();
+    let rsv = sign().signature;
+    let formats = [
+        (SignatureFormat::Recoverable, rsv.clone()),
+        (SignatureFormat::Compact, rsv[..64].to_vec()),
+        (
+            SignatureFormat::Ethereum,
+            [&rsv[..64], &[rsv[64] + 27]].concat(),
+        ),
+        (
+            SignatureFormat::EIP155(1),
+            [&rsv[..64], &[rsv[64] + 37]].concat(),
+        ),
+    ];
+    for (format, expected) in formats {
+        let bundle = sign().with_format(format).unwrap();
+        assert_eq!(bundle.signature, expected);
+        assert_eq!(bundle.signature_format, format);
+    }
+    for format in [
+        SignatureFormat::EIP155(1_000_000),
+        SignatureFormat::EIP2098,
+        SignatureFormat::Der,
+    ] {
+        let bundle = sign().with_format(format).unwrap();
+        assert_eq!(
+            utils::check_signature(
+                &bundle.digest,
+                &bundle.signature,
+                &bundle.publickey,
+                HashAlgorithm::Keccak256
+            ),
+            Ok(())
+        );
+    }
+    assert_eq!(
+        sign()
+            .with_format(SignatureFormat::EIP155(1_000_000))
+            .unwrap()
+            .signature
+            .len(),
+        64 + 3
+    );
+
+    let compact = sign().with_format(SignatureFormat::Compact).unwrap();
+    assert!(compact.with_format(SignatureFormat::Ethereum).is_err());
+
+    State::set_raw_privkey_enabled(true);
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_digest","params":{{"privkey":"{}","digest":"{}","signature_format":"Compact","signature_encoding":"Base64"}},"id":1}}"#,
+        privkey, digest
+    ));
+    assert_eq!(status_code, 200);
+    assert_eq!(reply["result"], base64::encode(&rsv[..64]));
+    let (status_code, reply) = post_rpc(&format!(
+        r#"{{"jsonrpc":"2.0","method":"sign_message","params":{{"privkey":"{}","message":"00","signature_format":{{"EIP155":1}}}},"id":2}}"#,
+        privkey
+    ));
+    assert_eq!(status_code, 200);
+    let signature = hexstr_to_vec(reply["result"]["signature"].as_str().unwrap()).unwrap();
+    assert!(signature[64] == 37 || signature[64] == 38);
+}
+
+#[test]
+fn test_public_key_encodings() {
+    let privkey = ECDSAPrivateKey::from_string(&format!("{:064x}", 1)).unwrap();
+    let generator = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let uncompressed = privkey.to_pubkey().unwrap();
+    assert_eq!(
+        vec8_to_hexstr(&uncompressed),
+        format!(
+            "04{}483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8",
+            generator
+        )
+    );
+
+    let pubkey = types::ECDSAPublicKey::from_vec8(&uncompressed).unwrap();
+    let encode = |encoding| pubkey.encode(encoding).unwrap();
+    let binary = |encoding| match encode(encoding) {
+        EncodedPublicKey::Binary(bytes) => bytes,
+        EncodedPublicKey::Text(text) => panic!("{:?} is text: {}", encoding, text),
+    };
+    let text = |encoding| match encode(encoding) {
+        EncodedPublicKey::Text(text) => text,
+        EncodedPublicKey::Binary(_) => panic!("{:?} is binary", encoding),
+    };
+
+    let compressed = binary(PublicKeyEncoding::Compressed);
+    assert_eq!(vec8_to_hexstr(&compressed), format!("02{}", generator));
+    assert_eq!(
+        types::ECDSAPublicKey::from_vec8(&compressed)
+            .unwrap()
+            .to_vec8(),
+        uncompressed
+    );
+    assert_eq!(binary(PublicKeyEncoding::Uncompressed), uncompressed);
+    assert_eq!(vec8_to_hexstr(&binary(PublicKeyEncoding::XOnly)), generator);
+    let spki = binary(PublicKeyEncoding::SpkiDer);
+    assert_eq!(
+        vec8_to_hexstr(&spki),
+        format!(
+            "3056301006072a8648ce3d020106052b8104000a034200{}",
+            vec8_to_hexstr(&uncompressed)
+        )
+    );
+    let pem = text(PublicKeyEncoding::Pem);
+    assert!(pem.starts_with("-----BEGIN PUBLIC KEY-----\n"));
+    assert!(pem.replace('\n', "").contains(&base64::encode(&spki)));
+
+    let jwk: Value = serde_json::from_str(&text(PublicKeyEncoding::Jwk)).unwrap();
+    assert_eq!(jwk["crv"], "secp256k1");
+    assert_eq!(
+        base64::decode_config(jwk["x"].as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap(),
+        uncompressed[1..33]
+    );
+
+    assert!(types::ECDSAPublicKey::from_vec8(&[4; 65]).is_err());
+}
+
+#[test]
+fn test_rpc_get_address() {
+    let (status_code, reply) = post_rpc(
+        r#"{"jsonrpc":"2.0","method":"get_address","params":["1","key",{"BitcoinP2WPKH":"Mainnet"}],"id":1}"#,
+    );
+    assert_eq!(status_code, 401);
+    assert_eq!(reply["error"]["code"], jsonrpc::UNAUTHORIZED);
+    let (status_code, reply) = post_rpc(
+        r#"{"jsonrpc":"2.0","method":"get_address","params":["1","key","Bitcoin"],"id":2}"#,
+    );
+    assert_eq!(status_code, 400);
+    assert_eq!(reply["error"]["code"], jsonrpc::INVALID_PARAMS);
+}
+
+#[test]
+fn test_caller_derivation_path() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+    let path = caller_derivation_path(&alice, vec![vec![7]]).unwrap();
+    assert_eq!(path, vec![vec![1; 29], vec![7]]);
+    assert_ne!(
+        caller_derivation_path(&alice, vec![]).unwrap(),
+        caller_derivation_path(&bob, vec![]).unwrap()
+    );
+    assert!(matches!(
+        caller_derivation_path(&alice, vec![vec![]; MAX_DERIVATION_SUB_PATH_LEN + 1]),
+        Err(SignerError::InvalidArgument(_))
+    ));
+    assert_eq!(
+        caller_derivation_path(&Principal::anonymous(), vec![]),
+        Err(SignerError::Unauthorized)
+    );
+}
+
+#[test]
+fn test_sign_with_key() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let expected = sign_digest(digest, &parse_privkey(privkey), HashAlgorithm::SHA3_256).unwrap();
+    State::rotate_kek(&[7; 32]).unwrap();
+    let info = KeyInfo {
+        key_id: "1".to_string(),
+        publickey: expected.publickey.clone(),
+        created_at: 0,
+        curve: Curve::Secp256k1,
+        hash_algorithms: HashAlgorithm::all(),
+        status: KeyStatus::Active,
+        alias: Some("main".to_string()),
+        description: None,
+        tags: vec![],
+        migration: None,
+    };
+    State::set_privkey(&alice, &info, privkey).unwrap();
+
+    let mgmt = MockManagementCanister::new(b"seed");
+    let msg_hash = hexstr_to_vec(digest).unwrap();
+    let sign = |caller, key_ref: &str| {
+        let key = KeyDescriptor::Local(key_ref.to_string());
+        backend::block_on(sign_with_key(
+            &mgmt,
+            caller,
+            key,
+            &msg_hash,
+            HashAlgorithm::SHA3_256,
+        ))
+    };
+    for key_ref in ["1", "main"] {
+        let bundle = sign(&alice, key_ref).unwrap();
+        assert_eq!(bundle.publickey, expected.publickey);
+        assert_eq!(bundle.signature, expected.signature);
+        assert_eq!(bundle.signature_format, SignatureFormat::Recoverable);
+        assert_eq!(bundle.hash_algorithm, HashAlgorithm::SHA3_256);
+    }
+    assert!(matches!(sign(&alice, "2"), Err(SignerError::KeyNotFound)));
+    assert!(sign(&bob, "main").is_err());
+}
+
+#[test]
+fn test_migrate_local_key() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    let local = ECDSAPrivateKey::from_string(privkey).unwrap();
+    State::rotate_kek(&[7; 32]).unwrap();
+    let info = KeyInfo {
+        key_id: "1".to_string(),
+        publickey: local.to_pubkey().unwrap(),
+        created_at: 0,
+        curve: Curve::Secp256k1,
+        hash_algorithms: vec![HashAlgorithm::SHA2_256],
+        status: KeyStatus::Active,
+        alias: None,
+        description: None,
+        tags: vec![],
+        migration: None,
+    };
+    State::set_privkey(&alice, &info, privkey).unwrap();
+
+    let mgmt = MockManagementCanister::new(b"seed");
+    let target = ThresholdKey::new(
+        &mgmt,
+        caller_derivation_path(&alice, migration_sub_path("1")).unwrap(),
+    );
+    let target_publickey = backend::block_on(target.public_key()).unwrap();
+    let target_publickey = types::ECDSAPublicKey::from_vec8(&target_publickey)
+        .unwrap()
+        .to_vec8();
+    let migrate = |now| {
+        backend::block_on(migrate_local_key(
+            &alice,
+            "1",
+            migration_sub_path("1"),
+            &target,
+            now,
+        ))
+    };
+    let res = migrate(1000).unwrap();
+    assert_eq!(res.migration.publickey, target_publickey);
+    assert_eq!(res.migration.derivation_path, migration_sub_path("1"));
+    assert_eq!(
+        res.migration.destroy_after,
+        1000 + KEY_MIGRATION_GRACE_PERIOD
+    );
+    let msg_hash = hash_sha256(res.attestation.as_bytes());
+    assert!(res.attestation.contains(&vec8_to_hexstr(&info.publickey)));
+    assert!(res.attestation.contains(&vec8_to_hexstr(&target_publickey)));
+    for (bundle, publickey) in [
+        (&res.local_signature, &info.publickey),
+        (&res.threshold_signature, &target_publickey),
+    ] {
+        assert_eq!(bundle.digest, msg_hash);
+        assert_eq!(&bundle.publickey, publickey);
+        assert!(utils::verify_signature(
+            &msg_hash,
+            &bundle.signature,
+            publickey,
+            HashAlgorithm::SHA2_256
+        ));
+    }
+
+    // Deprecated keys keep signing until the grace period is over
+    let stored = State::get_key_info(&alice, "1").unwrap();
+    assert_eq!(stored.migration, Some(res.migration.clone()));
+    assert_eq!(stored.status, KeyStatus::Deprecated);
+    assert_eq!(State::list_keys(&alice)[0].status, KeyStatus::Deprecated);
+    assert!(State::get_privkey(&alice, "1", HashAlgorithm::SHA2_256).is_ok());
+    assert!(matches!(
+        migrate(2000),
+        Err(SignerError::InvalidArgument(_))
+    ));
+    assert!(State::destroy_migrated_key(&alice, "1", res.migration.destroy_after - 1).is_err());
+    State::destroy_migrated_key(&alice, "1", res.migration.destroy_after).unwrap();
+    assert_eq!(
+        State::get_privkey(&alice, "1", HashAlgorithm::SHA2_256).err(),
+        Some(SignerError::KeyArchived)
+    );
+}
+
+#[test]
+fn test_generate_apikey() {
+    let mgmt = MockManagementCanister::new(b"seed");
+    let alice = Principal::from_slice(&[1; 29]);
+    let generate = |args| backend::block_on(create_apikey(&mgmt, &alice, args, 1000));
+
+    let first = generate(ApiKeyGenArgs::default()).unwrap();
+    assert_eq!(first.name, "key-1");
+    let second = generate(ApiKeyGenArgs {
+        name: Some("ci".to_string()),
+        expires_at: Some(2000),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(second.name, "ci");
+    assert_ne!(first.api_key, second.api_key);
+
+    let (caller, info) = State::get_caller_by_apikey(&second.api_key).unwrap();
+    assert_eq!(caller, alice);
+    assert_eq!(info.created_at, 1000);
+    assert_eq!(info.expires_at, Some(2000));
+    assert_eq!(State::list_apikeys(&alice).len(), 2);
+    assert!(matches!(
+        generate(ApiKeyGenArgs {
+            expires_at: Some(1000),
+            ..Default::default()
+        }),
+        Err(SignerError::ApiKeyExpired)
+    ));
+    assert!(matches!(
+        backend::block_on(create_apikey(
+            &mgmt,
+            &Principal::anonymous(),
+            ApiKeyGenArgs::default(),
+            1000
+        )),
+        Err(SignerError::Unauthorized)
+    ));
+}
+
+#[test]
+fn test_generate_privkey() {
+    let mgmt = MockManagementCanister::new(b"seed");
+    let alice = Principal::from_slice(&[1; 29]);
+    let generate = |args| backend::block_on(create_privkey(&mgmt, &alice, args, 1000));
+
+    let first = generate(PrivkeyGenArgs {
+        alias: Some("main".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+    let second = generate(PrivkeyGenArgs::default()).unwrap();
+    assert_ne!(first.key_id, second.key_id);
+    assert_ne!(first.publickey, second.publickey);
+    let info = State::get_key_info(&alice, &first.key_id).unwrap();
+    assert_eq!(info.publickey, first.publickey);
+    assert_eq!(info.created_at, 1000);
+    assert_eq!(info.alias.as_deref(), Some("main"));
+    assert!(matches!(
+        backend::block_on(create_privkey(
+            &mgmt,
+            &Principal::anonymous(),
+            PrivkeyGenArgs::default(),
+            1000
+        )),
+        Err(SignerError::Unauthorized)
+    ));
+
+    // The key signs, and its signatures verify against the returned public key
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let msg_hash = hexstr_to_vec(digest).unwrap();
+    let bundle = backend::block_on(sign_with_key(
+        &mgmt,
+        &alice,
+        KeyDescriptor::Local("main".to_string()),
+        &msg_hash,
+        HashAlgorithm::Keccak256,
+    ))
+    .unwrap();
+    assert_eq!(bundle.publickey, first.publickey);
+    assert_eq!(
+        recover_pubkey(&msg_hash, &bundle.signature).unwrap(),
+        first.publickey
+    );
+}
+
+#[test]
+fn test_reseal_privkeys() {
+    let mgmt = MockManagementCanister::new(b"seed");
+    let alice = Principal::from_slice(&[1; 29]);
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    State::import_plain_privkey(&alice, "0", privkey, 0).unwrap();
+    assert!(!State::has_kek());
+
+    // The first key-encryption key is generated for the keys imported in plaintext
+    assert_eq!(backend::block_on(reseal_privkeys(&mgmt)), Ok(true));
+    assert!(State::has_kek());
+    assert!(!State::reseal_pending());
+    assert_eq!(backend::block_on(reseal_privkeys(&mgmt)), Ok(true));
+    assert_eq!(
+        State::get_privkey(&alice, "0", HashAlgorithm::Keccak256)
+            .unwrap()
+            .to_string(),
+        privkey
+    );
+}
+
+#[test]
+fn test_sign_digest_ic() {
+    let mgmt = MockManagementCanister::new(b"seed");
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+    let digest = "369183d3786773cef4e56c7b849e7ef5f742867510b676d6b38f8e38a222d8a2";
+    let msg_hash = hexstr_to_vec(digest).unwrap();
+    let sign = |path: &Vec<Vec<u8>>, format| {
+        backend::block_on(sign_with_threshold_key(
+            &mgmt,
+            digest,
+            None,
+            format,
+            path.clone(),
+        ))
+    };
+
+    let path = caller_derivation_path(&alice, vec![vec![1]]).unwrap();
+    let bundle = sign(&path, None).unwrap();
+    assert_eq!(bundle.signature_format, SignatureFormat::Compact);
+    assert_eq!(bundle.signature.len(), 64);
+    let key = backend::block_on(ThresholdKey::new(&mgmt, path.clone()).ic_public_key()).unwrap();
+    assert_eq!(key.public_key.len(), 33);
+    assert_eq!(key.chain_code.len(), 32);
+    assert_eq!(
+        bundle.publickey,
+        types::ECDSAPublicKey::from_vec8(&key.public_key)
+            .unwrap()
+            .to_vec8()
+    );
+    assert!(utils::verify_signature(
+        &msg_hash,
+        &bundle.signature,
+        &key.public_key,
+        HashAlgorithm::Keccak256
+    ));
+    assert_eq!(
+        State::get_ic_public_key(&State::ecdsa_key_name(), &path),
+        Some(key.clone())
+    );
+
+    // The recovery id is found, so the signature recovers to the threshold key
+    let bundle = sign(&path, Some(SignatureFormat::Recoverable)).unwrap();
+    assert_eq!(
+        recover_pubkey(&msg_hash, &bundle.signature).unwrap(),
+        bundle.publickey
+    );
+
+    // `sign` with a threshold descriptor uses the same key
+    let unified = backend::block_on(sign_with_key(
+        &mgmt,
+        &alice,
+        KeyDescriptor::Threshold(vec![vec![1]]),
+        &msg_hash,
+        HashAlgorithm::Keccak256,
+    ))
+    .unwrap();
+    assert_eq!(unified.publickey, bundle.publickey);
+
+    // Other principals get other keys
+    let bob_path = caller_derivation_path(&bob, vec![vec![1]]).unwrap();
+    assert_ne!(sign(&bob_path, None).unwrap().publickey, bundle.publickey);
+
+    // Signatures which don't match the key are rejected
+    let other_path = caller_derivation_path(&alice, vec![vec![2]]).unwrap();
+    State::cache_ic_public_key(&State::ecdsa_key_name(), &other_path, &key);
+    assert!(matches!(
+        sign(&other_path, None),
+        Err(SignerError::VerificationFailed)
+    ));
 }
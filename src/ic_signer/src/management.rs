@@ -0,0 +1,189 @@
+use crate::error::SignerError;
+#[cfg(test)]
+use crate::types::{
+    ECDSAPrivateKey, ECDSAPublicKey, EncodedPublicKey, HashAlgorithm, PrivateKey, PublicKey,
+    PublicKeyEncoding,
+};
+#[cfg(test)]
+use crate::utils::hash_sha256;
+use ic_cdk::api::call::call_with_payment;
+#[cfg(test)]
+use ic_cdk::export::candid::Encode;
+use ic_cdk::export::{
+    candid::CandidType,
+    serde::{Deserialize as SerdeDeserialize, Serialize},
+    Principal,
+};
+
+/// The calls made to the management canister, so they can be mocked under `cargo test`.
+pub trait ManagementCanister {
+    /// 32 random bytes
+    async fn raw_rand(&self) -> Result<Vec<u8>, SignerError>;
+
+    async fn ecdsa_public_key(
+        &self,
+        args: ECDSAPublicKeyArgs,
+    ) -> Result<ECDSAPublicKeyReply, SignerError>;
+
+    async fn sign_with_ecdsa(&self, args: SignWithECDSA)
+        -> Result<SignWithECDSAReply, SignerError>;
+}
+
+/// The management canister of the subnet, `aaaaa-aa`
+pub struct Ic00;
+
+impl ManagementCanister for Ic00 {
+    async fn raw_rand(&self) -> Result<Vec<u8>, SignerError> {
+        let (rnd_buf,): (Vec<u8>,) = ic_cdk::call(Principal::management_canister(), "raw_rand", ())
+            .await
+            .map_err(|e| SignerError::ManagementCanister(format!("raw_rand: {}", e.1)))?;
+        Ok(rnd_buf)
+    }
+
+    async fn ecdsa_public_key(
+        &self,
+        args: ECDSAPublicKeyArgs,
+    ) -> Result<ECDSAPublicKeyReply, SignerError> {
+        // Fetching a public key is free, unlike signing
+        let ic00 = Principal::management_canister();
+        let (res,): (ECDSAPublicKeyReply,) = ic_cdk::call(ic00, "ecdsa_public_key", (args,))
+            .await
+            .map_err(|e| SignerError::ManagementCanister(format!("ecdsa_public_key: {}", e.1)))?;
+        Ok(res)
+    }
+
+    async fn sign_with_ecdsa(
+        &self,
+        args: SignWithECDSA,
+    ) -> Result<SignWithECDSAReply, SignerError> {
+        let fee = sign_with_ecdsa_fee(&args.key_id);
+        if ic_cdk::api::canister_balance() < fee {
+            return Err(SignerError::InsufficientCycles(fee));
+        }
+        let ic00 = Principal::management_canister();
+        let (res,): (SignWithECDSAReply,) =
+            call_with_payment(ic00, "sign_with_ecdsa", (args,), fee)
+                .await
+                .map_err(|e| {
+                    SignerError::ManagementCanister(format!("sign_with_ecdsa: {}", e.1))
+                })?;
+        Ok(res)
+    }
+}
+
+/// Cycles attached to `sign_with_ecdsa`. The production key lives on a larger subnet
+/// and costs more; `test_key_1` and the local `dfx_test_key` cost the base fee.
+pub fn sign_with_ecdsa_fee(key_id: &EcdsaKeyId) -> u64 {
+    match key_id.name.as_str() {
+        "key_1" => 26_153_846_153,
+        _ => 10_000_000_000,
+    }
+}
+
+type CanisterId = Principal;
+
+#[derive(CandidType, Serialize, Debug)]
+pub struct ECDSAPublicKeyArgs {
+    pub canister_id: Option<CanisterId>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: EcdsaKeyId,
+}
+
+#[derive(CandidType, SerdeDeserialize, Debug)]
+pub struct ECDSAPublicKeyReply {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Debug)]
+pub struct SignWithECDSA {
+    pub message_hash: Vec<u8>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: EcdsaKeyId,
+}
+
+#[derive(CandidType, SerdeDeserialize, Debug)]
+pub struct SignWithECDSAReply {
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Debug, Clone)]
+pub struct EcdsaKeyId {
+    pub curve: EcdsaCurve,
+    pub name: String,
+}
+
+#[derive(CandidType, Serialize, Debug, Clone)]
+pub enum EcdsaCurve {
+    #[serde(rename = "secp256k1")]
+    Secp256k1,
+}
+
+/// Stands in for the management canister in tests. Randomness is a counter, and
+/// threshold keys are local keys derived from `seed`, the key name and the path.
+#[cfg(test)]
+pub struct MockManagementCanister {
+    seed: Vec<u8>,
+    rand_calls: std::cell::Cell<u64>,
+}
+
+#[cfg(test)]
+impl MockManagementCanister {
+    pub fn new(seed: &[u8]) -> MockManagementCanister {
+        MockManagementCanister {
+            seed: seed.to_vec(),
+            rand_calls: std::cell::Cell::new(0),
+        }
+    }
+
+    fn derive(
+        &self,
+        key_id: &EcdsaKeyId,
+        derivation_path: &[Vec<u8>],
+    ) -> (ECDSAPrivateKey, Vec<u8>) {
+        let path = Encode!(&key_id.name, &derivation_path).unwrap();
+        let privkey = hash_sha256(&[&self.seed[..], &path].concat());
+        let chain_code = hash_sha256(&[&privkey[..], b"chain code"].concat());
+        let privkey = ECDSAPrivateKey::from_string(&hex::encode(privkey)).unwrap();
+        (privkey, chain_code)
+    }
+}
+
+#[cfg(test)]
+impl ManagementCanister for MockManagementCanister {
+    async fn raw_rand(&self) -> Result<Vec<u8>, SignerError> {
+        let calls = self.rand_calls.get() + 1;
+        self.rand_calls.set(calls);
+        Ok(hash_sha256(&calls.to_be_bytes()))
+    }
+
+    async fn ecdsa_public_key(
+        &self,
+        args: ECDSAPublicKeyArgs,
+    ) -> Result<ECDSAPublicKeyReply, SignerError> {
+        let (privkey, chain_code) = self.derive(&args.key_id, &args.derivation_path);
+        let publickey = ECDSAPublicKey::from_vec8(&privkey.to_pubkey()?)?;
+        // The management canister returns compressed keys
+        let public_key = match publickey.encode(PublicKeyEncoding::Compressed)? {
+            EncodedPublicKey::Binary(key) => key,
+            EncodedPublicKey::Text(_) => unreachable!(),
+        };
+        Ok(ECDSAPublicKeyReply {
+            public_key,
+            chain_code,
+        })
+    }
+
+    async fn sign_with_ecdsa(
+        &self,
+        args: SignWithECDSA,
+    ) -> Result<SignWithECDSAReply, SignerError> {
+        let (privkey, _) = self.derive(&args.key_id, &args.derivation_path);
+        // The digest is signed as is, so any hash algorithm does
+        let signature = privkey.sign(&args.message_hash, HashAlgorithm::default())?;
+        // Threshold signatures come without the recovery id
+        Ok(SignWithECDSAReply {
+            signature: signature[..64].to_vec(),
+        })
+    }
+}
@@ -0,0 +1,1450 @@
+use crate::crypto::{constant_time_eq, open, salted_hash, seal, KEK_LEN, NONCE_LEN};
+use crate::error::SignerError;
+use crate::types::{
+    ApiKeyInfo, ApiKeyScope, Curve, ECDSAPrivateKey, HashAlgorithm, IcPublicKey, KeyInfo,
+    KeyMigration, KeyStatus, PrivateKey,
+};
+use crate::utils::{hash_sha256, hexstr_to_vec};
+use ic_cdk::{
+    api,
+    export::{
+        candid::{CandidType, Decode, Deserialize, Encode},
+        Principal,
+    },
+    storage,
+};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, ops::Bound};
+use zeroize::Zeroizing;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const PRINCIPAL_MAX_LEN: usize = 29;
+const KEY_ID_MAX_LEN: usize = 64;
+const ALIAS_MAX_LEN: usize = 64;
+const DESCRIPTION_MAX_LEN: usize = 256;
+const TAG_MAX_LEN: usize = 32;
+const TAGS_MAX_COUNT: usize = 16;
+const KEY_RECORD_MAX_SIZE: u32 = 2048;
+const API_KEY_RECORD_MAX_SIZE: u32 = 4096;
+const API_KEY_NAME_MAX_LEN: usize = 32;
+const API_KEY_SCOPE_MAX_ITEMS: usize = 32;
+const API_KEY_HASH_LEN: usize = 32;
+const IC_PUBLIC_KEY_MAX_SIZE: u32 = 256;
+const DERIVATION_PATH_HASH_LEN: usize = 32;
+// Threshold keys cached per principal, the keys of further paths are fetched every time
+const IC_PUBLIC_KEYS_PER_PRINCIPAL: u64 = 64;
+
+const PRIVKEYS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const API_KEYS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(2);
+const KEK_MEMORY_ID: MemoryId = MemoryId::new(3);
+const KEY_COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ALIASES_MEMORY_ID: MemoryId = MemoryId::new(5);
+const API_KEY_HASHES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const IC_PUBLIC_KEYS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const IC_PUBLIC_KEY_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(8);
+
+type StablePrincipal = ic_stable_structures::storable::Blob<PRINCIPAL_MAX_LEN>;
+type StableKeyId = ic_stable_structures::storable::Blob<KEY_ID_MAX_LEN>;
+type StableAlias = ic_stable_structures::storable::Blob<ALIAS_MAX_LEN>;
+type StableApiKeyName = ic_stable_structures::storable::Blob<API_KEY_NAME_MAX_LEN>;
+type StableApiKeyHash = ic_stable_structures::storable::Blob<API_KEY_HASH_LEN>;
+type StablePathHash = ic_stable_structures::storable::Blob<DERIVATION_PATH_HASH_LEN>;
+
+// Keys of one principal are contiguous since the tuple is ordered by the principal first
+type PrivkeyId = (StablePrincipal, StableKeyId);
+type ApiKeyId = (StablePrincipal, StableApiKeyName);
+
+/// A private key as kept in stable memory.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum StoredPrivkey {
+    /// Imported from a release without encryption at rest, until `reseal_privkeys` seals it
+    Plain(String),
+    Sealed {
+        kek_version: u32,
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct KeyRecord {
+    /// `None` once the key is archived
+    pub privkey: Option<StoredPrivkey>,
+    pub publickey: Vec<u8>,
+    pub created_at: u64,
+    pub curve: Curve,
+    pub hash_algorithms: Vec<HashAlgorithm>,
+    pub status: KeyStatus,
+    pub alias: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub migration: Option<KeyMigration>,
+}
+
+impl KeyRecord {
+    fn to_info(&self, key_id: &StableKeyId) -> KeyInfo {
+        KeyInfo {
+            key_id: String::from_utf8_lossy(key_id.as_slice()).to_string(),
+            publickey: self.publickey.clone(),
+            created_at: self.created_at,
+            curve: self.curve,
+            hash_algorithms: self.hash_algorithms.clone(),
+            status: match (self.status, &self.migration) {
+                (KeyStatus::Active, Some(_)) => KeyStatus::Deprecated,
+                (status, _) => status,
+            },
+            alias: self.alias.clone(),
+            description: self.description.clone(),
+            tags: self.tags.clone(),
+            migration: self.migration.clone(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ApiKeyRecord {
+    /// Salted hash of the API key, the key itself is never stored
+    pub hash: Vec<u8>,
+    pub scope: ApiKeyScope,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl ApiKeyRecord {
+    fn to_info(&self, name: &StableApiKeyName) -> ApiKeyInfo {
+        ApiKeyInfo {
+            name: String::from_utf8_lossy(name.as_slice()).to_string(),
+            scope: self.scope.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Config {
+    /// Allowed to call admin methods, the principal which installed the canister
+    /// unless given in the init or upgrade args
+    pub owner: Option<Principal>,
+    /// Salt of the API key hashes, set before the first API key is stored
+    pub apikey_salt: Option<Vec<u8>>,
+    /// Whether the HTTP gateway signs with raw private keys passed in the params,
+    /// off when unset
+    pub raw_privkey_enabled: Option<bool>,
+    /// Name of the threshold ECDSA key of the subnet, `DEFAULT_ECDSA_KEY_NAME` when unset
+    pub ecdsa_key_name: Option<String>,
+}
+
+/// The key of the local replica, subnets have `test_key_1` or `key_1`
+pub const DEFAULT_ECDSA_KEY_NAME: &str = "dfx_test_key";
+
+/// The canister key-encryption key. Version 0 means none has been generated yet.
+///
+/// It is stored in plaintext in the same stable memory as the sealed private keys.
+/// Sealing keeps the keys out of individual records, but does not protect them
+/// from anyone who can read or dump the canister memory, e.g. the subnet nodes.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct KeyEncryptionKey {
+    version: u32,
+    key: Vec<u8>,
+    // Nonces are (version, counter), so they never repeat under the same key
+    nonce_counter: u64,
+    /// Earlier (version, key) pairs, kept until no private key is sealed under them
+    retired: Option<Vec<(u32, Vec<u8>)>>,
+    /// Where `State::reseal_privkeys` resumes, `None` once every private key is
+    /// sealed under the current version
+    reseal_cursor: Option<ResealCursor>,
+}
+
+impl KeyEncryptionKey {
+    fn key(&self, version: u32) -> Option<&[u8]> {
+        if version == self.version {
+            return Some(&self.key);
+        }
+        self.retired
+            .iter()
+            .flatten()
+            .find(|(retired_version, _)| *retired_version == version)
+            .map(|(_, key)| key.as_slice())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ResealCursor {
+    Start,
+    /// The principal and the key ID of the last record visited
+    After(Vec<u8>, Vec<u8>),
+}
+
+/// Version of the layout of the stored records, written as their first byte. Changing
+/// a non-optional field of a record takes a new version, and `record_candid` then has to
+/// convert the records of the earlier layouts as they are read.
+const RECORD_VERSION: u8 = 1;
+
+// Splits a stored record into its layout version and its Candid encoding. Records
+// written before versioning are plain Candid, which starts with `DIDL`.
+fn record_candid(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes {
+        [b'D', b'I', b'D', b'L', ..] => (0, bytes),
+        [version, candid @ ..] => (*version, candid),
+        [] => panic!("Empty stored record"),
+    }
+}
+
+// Records are stored as Candid, so new optional fields can be added without a migration
+macro_rules! impl_candid_storable {
+    ($type:ty) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                let mut bytes = vec![RECORD_VERSION];
+                bytes.extend(Encode!(self).unwrap());
+                Cow::Owned(bytes)
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                match record_candid(bytes.as_ref()) {
+                    // Version 0 only lacks the version byte
+                    (0 | RECORD_VERSION, candid) => Decode!(candid, Self).unwrap_or_else(|e| {
+                        panic!("Failed to decode a stored {}: {}", stringify!($type), e)
+                    }),
+                    (version, _) => panic!(
+                        "Stored {} has layout version {}, newer than this release",
+                        stringify!($type),
+                        version
+                    ),
+                }
+            }
+        }
+    };
+    ($type:ty, $max_size:expr) => {
+        impl_candid_storable!($type);
+
+        impl BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+}
+
+impl_candid_storable!(KeyRecord, KEY_RECORD_MAX_SIZE);
+impl_candid_storable!(ApiKeyRecord, API_KEY_RECORD_MAX_SIZE);
+impl_candid_storable!(Config);
+impl_candid_storable!(KeyEncryptionKey);
+impl_candid_storable!(IcPublicKey, IC_PUBLIC_KEY_MAX_SIZE);
+
+fn stable_principal(principal: &Principal) -> StablePrincipal {
+    StablePrincipal::try_from(principal.as_slice()).unwrap()
+}
+
+fn stable_key_id(key_id: &str) -> Result<StableKeyId, SignerError> {
+    StableKeyId::try_from(key_id.as_bytes())
+        .map_err(|_| SignerError::InvalidLength(format!("key ID (max {} bytes)", KEY_ID_MAX_LEN)))
+}
+
+fn stable_alias(alias: &str) -> Result<StableAlias, SignerError> {
+    StableAlias::try_from(alias.as_bytes())
+        .map_err(|_| SignerError::InvalidLength(format!("alias (max {} bytes)", ALIAS_MAX_LEN)))
+}
+
+fn stable_apikey_name(name: &str) -> Result<StableApiKeyName, SignerError> {
+    if name.is_empty() {
+        return Err(SignerError::InvalidLength("API key name".to_string()));
+    }
+    StableApiKeyName::try_from(name.as_bytes()).map_err(|_| {
+        SignerError::InvalidLength(format!("API key name (max {} bytes)", API_KEY_NAME_MAX_LEN))
+    })
+}
+
+fn check_apikey_scope(scope: &ApiKeyScope) -> Result<(), SignerError> {
+    let too_long = |len: Option<usize>| len.is_some_and(|len| len > API_KEY_SCOPE_MAX_ITEMS);
+    if too_long(scope.key_ids.as_ref().map(Vec::len))
+        || too_long(scope.methods.as_ref().map(Vec::len))
+    {
+        return Err(SignerError::InvalidLength(format!(
+            "API key scope (max {} items)",
+            API_KEY_SCOPE_MAX_ITEMS
+        )));
+    }
+    Ok(())
+}
+
+fn check_key_labels(info: &KeyInfo) -> Result<(), SignerError> {
+    if let Some(alias) = &info.alias {
+        // Key IDs are numeric, so an alias never shadows one
+        if alias.is_empty() || alias.chars().all(|c| c.is_ascii_digit()) {
+            return Err(SignerError::InvalidAlias(alias.clone()));
+        }
+    }
+    if info.description.as_ref().map_or(0, |d| d.len()) > DESCRIPTION_MAX_LEN {
+        return Err(SignerError::InvalidLength(format!(
+            "description (max {} bytes)",
+            DESCRIPTION_MAX_LEN
+        )));
+    }
+    if info.tags.len() > TAGS_MAX_COUNT || info.tags.iter().any(|t| t.len() > TAG_MAX_LEN) {
+        return Err(SignerError::InvalidLength(format!(
+            "tags (max {} tags of {} bytes)",
+            TAGS_MAX_COUNT, TAG_MAX_LEN
+        )));
+    }
+    Ok(())
+}
+
+// Derivation paths are unbounded, so threshold keys are looked up by a hash of the key
+// name and the path
+fn ic_public_key_id(key_name: &str, derivation_path: &[Vec<u8>]) -> StablePathHash {
+    let encoded = Encode!(&key_name, &derivation_path).unwrap();
+    StablePathHash::try_from(&hash_sha256(&encoded)[..]).unwrap()
+}
+
+// Additional data of a sealed private key, so it can't be moved to another slot
+fn privkey_aad(id: &PrivkeyId) -> Vec<u8> {
+    [id.0.as_slice(), b":", id.1.as_slice()].concat()
+}
+
+pub struct State {
+    privkeys: StableBTreeMap<PrivkeyId, KeyRecord, Memory>,
+    api_keys: StableBTreeMap<ApiKeyId, ApiKeyRecord, Memory>,
+    // Reverse index of `api_keys` by the salted hash of the key
+    api_key_hashes: StableBTreeMap<StableApiKeyHash, ApiKeyId, Memory>,
+    config: StableCell<Config, Memory>,
+    kek: StableCell<KeyEncryptionKey, Memory>,
+    // The next key ID of each principal
+    key_counters: StableBTreeMap<StablePrincipal, u64, Memory>,
+    aliases: StableBTreeMap<(StablePrincipal, StableAlias), StableKeyId, Memory>,
+    // Threshold ECDSA keys already fetched from the management canister
+    ic_public_keys: StableBTreeMap<StablePathHash, IcPublicKey, Memory>,
+    // The number of `ic_public_keys` entries of each principal
+    ic_public_key_counts: StableBTreeMap<StablePrincipal, u64, Memory>,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE: RefCell<State> = RefCell::new(State::init());
+}
+
+impl State {
+    fn init() -> State {
+        MEMORY_MANAGER.with(|mm| {
+            let mm = mm.borrow();
+            State {
+                privkeys: StableBTreeMap::init(mm.get(PRIVKEYS_MEMORY_ID)),
+                api_keys: StableBTreeMap::init(mm.get(API_KEYS_MEMORY_ID)),
+                api_key_hashes: StableBTreeMap::init(mm.get(API_KEY_HASHES_MEMORY_ID)),
+                config: StableCell::init(mm.get(CONFIG_MEMORY_ID), Config::default())
+                    .expect("Failed to init config"),
+                kek: StableCell::init(mm.get(KEK_MEMORY_ID), KeyEncryptionKey::default())
+                    .expect("Failed to init key-encryption key"),
+                key_counters: StableBTreeMap::init(mm.get(KEY_COUNTERS_MEMORY_ID)),
+                aliases: StableBTreeMap::init(mm.get(ALIASES_MEMORY_ID)),
+                ic_public_keys: StableBTreeMap::init(mm.get(IC_PUBLIC_KEYS_MEMORY_ID)),
+                ic_public_key_counts: StableBTreeMap::init(mm.get(IC_PUBLIC_KEY_COUNTS_MEMORY_ID)),
+            }
+        })
+    }
+
+    pub fn list_apikeys(principal: &Principal) -> Vec<ApiKeyInfo> {
+        let owner = stable_principal(principal);
+        STATE.with(|state| {
+            let state = state.borrow();
+            state
+                .api_keys
+                .range((owner, StableApiKeyName::default())..)
+                .take_while(|((p, _), _)| *p == owner)
+                .map(|((_, name), record)| record.to_info(&name))
+                .collect()
+        })
+    }
+
+    /// Returns the first `key-<n>` name not used by an API key of the principal.
+    pub fn next_apikey_name(principal: &Principal) -> String {
+        let names: Vec<_> = State::list_apikeys(principal)
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        (names.len() + 1..)
+            .map(|n| format!("key-{}", n))
+            .find(|name| !names.contains(name))
+            .unwrap()
+    }
+
+    pub fn has_apikey_salt() -> bool {
+        STATE.with(|state| state.borrow().config.get().apikey_salt.is_some())
+    }
+
+    /// Sets the salt of the API key hashes unless one is set already. It can't be
+    /// changed afterwards, since that would invalidate every stored API key.
+    pub fn init_apikey_salt(salt: &[u8]) -> Result<(), SignerError> {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut config = state.config.get().clone();
+            if config.apikey_salt.is_some() {
+                return Ok(());
+            }
+            config.apikey_salt = Some(salt.to_vec());
+            state
+                .config
+                .set(config)
+                .map(|_| ())
+                .map_err(|e| SignerError::Storage(format!("{:?}", e)))
+        })
+    }
+
+    fn hash_apikey(&self, apikey: &str) -> Option<StableApiKeyHash> {
+        let salt = self.config.get().apikey_salt.as_ref()?;
+        StableApiKeyHash::try_from(&salted_hash(salt, apikey.as_bytes())[..]).ok()
+    }
+
+    pub fn get_caller_by_apikey(apikey: &str) -> Option<(Principal, ApiKeyInfo)> {
+        STATE.with(|state| {
+            let state = state.borrow();
+            let hash = state.hash_apikey(apikey)?;
+            let id = state.api_key_hashes.get(&hash)?;
+            let record = state.api_keys.get(&id)?;
+            if !constant_time_eq(&record.hash, hash.as_slice()) {
+                return None;
+            }
+            let caller = Principal::from_slice(id.0.as_slice());
+            Some((caller, record.to_info(&id.1)))
+        })
+    }
+
+    /// Stores the salted hash of `key`, the salt must have been set with `init_apikey_salt`.
+    pub fn add_apikey(
+        principal: &Principal,
+        info: &ApiKeyInfo,
+        key: &str,
+    ) -> Result<(), SignerError> {
+        let id = (stable_principal(principal), stable_apikey_name(&info.name)?);
+        check_apikey_scope(&info.scope)?;
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.api_keys.contains_key(&id) {
+                return Err(SignerError::ApiKeyExists);
+            }
+            let hash = state
+                .hash_apikey(key)
+                .ok_or_else(|| SignerError::Storage("No API key salt".to_string()))?;
+            if state.api_key_hashes.contains_key(&hash) {
+                return Err(SignerError::ApiKeyExists);
+            }
+            let record = ApiKeyRecord {
+                hash: hash.as_slice().to_vec(),
+                scope: info.scope.clone(),
+                created_at: info.created_at,
+                expires_at: info.expires_at,
+            };
+            if record.to_bytes().len() > API_KEY_RECORD_MAX_SIZE as usize {
+                return Err(SignerError::InvalidLength("API key scope".to_string()));
+            }
+            state.api_key_hashes.insert(hash, id);
+            state.api_keys.insert(id, record);
+            Ok(())
+        })
+    }
+
+    /// Replaces `old_key` with `new_key` in one step, keeping the name, scope and
+    /// expiry. Fails without changes if `old_key` is gone or `new_key` is taken.
+    pub fn rotate_apikey(
+        old_key: &str,
+        new_key: &str,
+        now: u64,
+    ) -> Result<(Principal, ApiKeyInfo), SignerError> {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let old_hash = state
+                .hash_apikey(old_key)
+                .ok_or(SignerError::ApiKeyNotFound)?;
+            let id = state
+                .api_key_hashes
+                .get(&old_hash)
+                .ok_or(SignerError::ApiKeyNotFound)?;
+            let new_hash = state
+                .hash_apikey(new_key)
+                .ok_or_else(|| SignerError::Storage("No API key salt".to_string()))?;
+            if state.api_key_hashes.contains_key(&new_hash) {
+                return Err(SignerError::ApiKeyExists);
+            }
+            let record = ApiKeyRecord {
+                hash: new_hash.as_slice().to_vec(),
+                created_at: now,
+                ..state.api_keys.get(&id).ok_or(SignerError::ApiKeyNotFound)?
+            };
+            state.api_key_hashes.remove(&old_hash);
+            state.api_key_hashes.insert(new_hash, id);
+            state.api_keys.insert(id, record.clone());
+            Ok((
+                Principal::from_slice(id.0.as_slice()),
+                record.to_info(&id.1),
+            ))
+        })
+    }
+
+    pub fn revoke_apikey(principal: &Principal, name: &str) -> Result<(), SignerError> {
+        let id = (stable_principal(principal), stable_apikey_name(name)?);
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let record = state
+                .api_keys
+                .remove(&id)
+                .ok_or(SignerError::ApiKeyNotFound)?;
+            if let Ok(hash) = StableApiKeyHash::try_from(record.hash.as_slice()) {
+                state.api_key_hashes.remove(&hash);
+            }
+            Ok(())
+        })
+    }
+
+    fn keys_of(&self, owner: StablePrincipal) -> impl Iterator<Item = (PrivkeyId, KeyRecord)> + '_ {
+        self.privkeys
+            .range((owner, StableKeyId::default())..)
+            .take_while(move |((p, _), _)| *p == owner)
+    }
+
+    /// Allocates the next key ID of a principal. IDs are never reused, even after
+    /// the key they were assigned to is deleted.
+    pub fn allocate_key_id(principal: &Principal) -> String {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let owner = stable_principal(principal);
+            let next = match state.key_counters.get(&owner) {
+                Some(next) => next,
+                // Keys created before the counter existed were numbered by count
+                None => state
+                    .keys_of(owner)
+                    .filter_map(|((_, key_id), _)| {
+                        std::str::from_utf8(key_id.as_slice())
+                            .ok()?
+                            .parse::<u64>()
+                            .ok()
+                    })
+                    .map(|id| id + 1)
+                    .max()
+                    .unwrap_or(0),
+            };
+            state.key_counters.insert(owner, next + 1);
+            next.to_string()
+        })
+    }
+
+    pub fn list_keys(principal: &Principal) -> Vec<KeyInfo> {
+        STATE.with(|state| {
+            let state = state.borrow();
+            state
+                .keys_of(stable_principal(principal))
+                .map(|((_, key_id), record)| record.to_info(&key_id))
+                .collect()
+        })
+    }
+
+    pub fn find_keys_by_tag(principal: &Principal, tag: &str) -> Vec<KeyInfo> {
+        STATE.with(|state| {
+            let state = state.borrow();
+            state
+                .keys_of(stable_principal(principal))
+                .filter(|(_, record)| record.tags.iter().any(|t| t == tag))
+                .map(|((_, key_id), record)| record.to_info(&key_id))
+                .collect()
+        })
+    }
+
+    /// Resolves a key ID or an alias to the key ID.
+    pub fn resolve_key_id(principal: &Principal, key_ref: &str) -> Result<String, SignerError> {
+        let owner = stable_principal(principal);
+        STATE.with(|state| {
+            let state = state.borrow();
+            if let Ok(key_id) = stable_key_id(key_ref) {
+                if state.privkeys.contains_key(&(owner, key_id)) {
+                    return Ok(key_ref.to_string());
+                }
+            }
+            let alias = stable_alias(key_ref).map_err(|_| SignerError::KeyNotFound)?;
+            match state.aliases.get(&(owner, alias)) {
+                Some(key_id) => Ok(String::from_utf8_lossy(key_id.as_slice()).to_string()),
+                None => Err(SignerError::KeyNotFound),
+            }
+        })
+    }
+
+    pub fn get_key_info(principal: &Principal, key_id: &str) -> Result<KeyInfo, SignerError> {
+        let id = (stable_principal(principal), stable_key_id(key_id)?);
+        STATE.with(|state| match state.borrow().privkeys.get(&id) {
+            Some(record) => Ok(record.to_info(&id.1)),
+            None => Err(SignerError::KeyNotFound),
+        })
+    }
+
+    pub fn get_owner() -> Option<Principal> {
+        STATE.with(|state| state.borrow().config.get().owner)
+    }
+
+    pub fn set_owner(principal: &Principal) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut config = state.config.get().clone();
+            config.owner = Some(*principal);
+            state.config.set(config).expect("Failed to save config");
+        })
+    }
+
+    pub fn raw_privkey_enabled() -> bool {
+        STATE.with(|state| {
+            state
+                .borrow()
+                .config
+                .get()
+                .raw_privkey_enabled
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn set_raw_privkey_enabled(enabled: bool) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut config = state.config.get().clone();
+            config.raw_privkey_enabled = Some(enabled);
+            state.config.set(config).expect("Failed to save config");
+        })
+    }
+
+    pub fn ecdsa_key_name() -> String {
+        STATE.with(|state| {
+            state
+                .borrow()
+                .config
+                .get()
+                .ecdsa_key_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_ECDSA_KEY_NAME.to_string())
+        })
+    }
+
+    pub fn set_ecdsa_key_name(name: &str) -> Result<(), SignerError> {
+        if name.is_empty() {
+            return Err(SignerError::InvalidArgument(
+                "ECDSA key name is empty".to_string(),
+            ));
+        }
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut config = state.config.get().clone();
+            config.ecdsa_key_name = Some(name.to_string());
+            state.config.set(config).expect("Failed to save config");
+        });
+        Ok(())
+    }
+
+    /// The cached threshold ECDSA key of `key_name` at `derivation_path`, if any.
+    pub fn get_ic_public_key(key_name: &str, derivation_path: &[Vec<u8>]) -> Option<IcPublicKey> {
+        let id = ic_public_key_id(key_name, derivation_path);
+        STATE.with(|state| state.borrow().ic_public_keys.get(&id))
+    }
+
+    /// Caches a threshold key. `derivation_path` starts with the principal it belongs
+    /// to, which may only cache `IC_PUBLIC_KEYS_PER_PRINCIPAL` keys.
+    pub fn cache_ic_public_key(key_name: &str, derivation_path: &[Vec<u8>], key: &IcPublicKey) {
+        let owner = match derivation_path
+            .first()
+            .and_then(|owner| StablePrincipal::try_from(owner.as_slice()).ok())
+        {
+            Some(owner) => owner,
+            None => return,
+        };
+        let id = ic_public_key_id(key_name, derivation_path);
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.ic_public_keys.contains_key(&id) {
+                return;
+            }
+            let count = state.ic_public_key_counts.get(&owner).unwrap_or(0);
+            if count >= IC_PUBLIC_KEYS_PER_PRINCIPAL {
+                return;
+            }
+            state.ic_public_key_counts.insert(owner, count + 1);
+            state.ic_public_keys.insert(id, key.clone());
+        });
+    }
+
+    pub fn has_kek() -> bool {
+        STATE.with(|state| state.borrow().kek.get().version > 0)
+    }
+
+    /// Replaces the key-encryption key, returning the version of the new key. The
+    /// earlier key is kept to open the private keys still sealed under it, until
+    /// `reseal_privkeys` has re-sealed all of them.
+    pub fn rotate_kek(new_key: &[u8]) -> Result<u32, SignerError> {
+        if new_key.len() != KEK_LEN {
+            return Err(SignerError::InvalidLength("key-encryption key".to_string()));
+        }
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let old_kek = state.kek.get().clone();
+            let mut retired = old_kek.retired.unwrap_or_default();
+            if old_kek.version > 0 {
+                retired.push((old_kek.version, old_kek.key));
+            }
+            let new_kek = KeyEncryptionKey {
+                version: old_kek.version + 1,
+                key: new_key.to_vec(),
+                nonce_counter: 0,
+                retired: Some(retired),
+                reseal_cursor: Some(ResealCursor::Start),
+            };
+            let version = new_kek.version;
+            state.kek.set(new_kek).map_err(|_| {
+                SignerError::Storage("Failed to save key-encryption key".to_string())
+            })?;
+            Ok(version)
+        })
+    }
+
+    pub fn reseal_pending() -> bool {
+        STATE.with(|state| state.borrow().kek.get().reseal_cursor.is_some())
+    }
+
+    /// Visits up to `batch_size` private keys, re-sealing those under a retired
+    /// key-encryption key or in plaintext. Returns `true` once all are sealed under
+    /// the current key, the retired keys are then dropped.
+    pub fn reseal_privkeys(batch_size: usize) -> Result<bool, SignerError> {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut kek = state.kek.get().clone();
+            let mut records: Vec<_> = match &kek.reseal_cursor {
+                None => return Ok(true),
+                Some(_) if kek.version == 0 => return Err(SignerError::NoKeyEncryptionKey),
+                Some(ResealCursor::Start) => state.privkeys.iter().take(batch_size + 1).collect(),
+                Some(ResealCursor::After(principal, key_id)) => {
+                    let last = (
+                        StablePrincipal::try_from(principal.as_slice()).unwrap(),
+                        StableKeyId::try_from(key_id.as_slice()).unwrap(),
+                    );
+                    state
+                        .privkeys
+                        .range((Bound::Excluded(last), Bound::Unbounded))
+                        .take(batch_size + 1)
+                        .collect()
+                }
+            };
+
+            // One more record is read to tell whether any is left after the batch
+            let done = records.len() <= batch_size;
+            records.truncate(batch_size);
+            let mut resealed = Vec::with_capacity(records.len());
+            let mut last = None;
+            for (id, mut record) in records {
+                last = Some(ResealCursor::After(
+                    id.0.as_slice().to_vec(),
+                    id.1.as_slice().to_vec(),
+                ));
+                match &record.privkey {
+                    Some(StoredPrivkey::Sealed { kek_version, .. })
+                        if *kek_version == kek.version => {}
+                    Some(privkey) => {
+                        let privkey = open_privkey(&kek, &id, privkey)?;
+                        record.privkey = Some(seal_privkey(&mut kek, &id, &privkey)?);
+                        resealed.push((id, record));
+                    }
+                    None => {}
+                }
+            }
+            if done {
+                kek.retired = None;
+                kek.reseal_cursor = None;
+            } else {
+                kek.reseal_cursor = last;
+            }
+
+            state.kek.set(kek).map_err(|_| {
+                SignerError::Storage("Failed to save key-encryption key".to_string())
+            })?;
+            for (id, record) in resealed {
+                state.privkeys.insert(id, record);
+            }
+            Ok(done)
+        })
+    }
+
+    /// Returns the private key for signing a digest hashed with `hash_algo`, wiped from
+    /// memory once dropped.
+    pub fn get_privkey(
+        principal: &Principal,
+        key_id: &str,
+        hash_algo: HashAlgorithm,
+    ) -> Result<Zeroizing<ECDSAPrivateKey>, SignerError> {
+        let id = (stable_principal(principal), stable_key_id(key_id)?);
+        STATE.with(|state| {
+            let state = state.borrow();
+            let record = state.privkeys.get(&id).ok_or(SignerError::KeyNotFound)?;
+            match record.status {
+                KeyStatus::Active | KeyStatus::Deprecated => {}
+                KeyStatus::Disabled => return Err(SignerError::KeyDisabled),
+                KeyStatus::Archived => return Err(SignerError::KeyArchived),
+            }
+            if !record.hash_algorithms.contains(&hash_algo) {
+                return Err(SignerError::HashAlgorithmNotAllowed);
+            }
+            match &record.privkey {
+                Some(privkey) => {
+                    let privkey = open_privkey(state.kek.get(), &id, privkey)?;
+                    Ok(Zeroizing::new(ECDSAPrivateKey::from_vec8(&privkey)?))
+                }
+                None => Err(SignerError::KeyArchived),
+            }
+        })
+    }
+
+    pub fn set_privkey(
+        principal: &Principal,
+        info: &KeyInfo,
+        key: &str,
+    ) -> Result<(), SignerError> {
+        let id = (stable_principal(principal), stable_key_id(&info.key_id)?);
+        check_key_labels(info)?;
+        let alias = match &info.alias {
+            Some(alias) => Some((id.0, stable_alias(alias)?)),
+            None => None,
+        };
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.privkeys.contains_key(&id) {
+                return Err(SignerError::KeyIdExists);
+            }
+            if let Some(alias) = &alias {
+                if state.aliases.contains_key(alias) {
+                    return Err(SignerError::AliasExists);
+                }
+            }
+            let mut kek = state.kek.get().clone();
+            let record = KeyRecord {
+                privkey: Some(seal_privkey(
+                    &mut kek,
+                    &id,
+                    &Zeroizing::new(hexstr_to_vec(key)?),
+                )?),
+                publickey: info.publickey.clone(),
+                created_at: info.created_at,
+                curve: info.curve,
+                hash_algorithms: info.hash_algorithms.clone(),
+                status: info.status,
+                alias: info.alias.clone(),
+                description: info.description.clone(),
+                tags: info.tags.clone(),
+                migration: info.migration.clone(),
+            };
+            if record.to_bytes().len() > KEY_RECORD_MAX_SIZE as usize {
+                return Err(SignerError::InvalidLength("key metadata".to_string()));
+            }
+            state.kek.set(kek).map_err(|_| {
+                SignerError::Storage("Failed to save key-encryption key".to_string())
+            })?;
+            if let Some(alias) = alias {
+                state.aliases.insert(alias, id.1);
+            }
+            state.privkeys.insert(id, record);
+            Ok(())
+        })
+    }
+
+    fn update_key(
+        principal: &Principal,
+        key_id: &str,
+        f: impl FnOnce(&mut KeyRecord) -> Result<(), SignerError>,
+    ) -> Result<(), SignerError> {
+        let id = (stable_principal(principal), stable_key_id(key_id)?);
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut record = state.privkeys.get(&id).ok_or(SignerError::KeyNotFound)?;
+            f(&mut record)?;
+            state.privkeys.insert(id, record);
+            Ok(())
+        })
+    }
+
+    /// Enables or disables signing with a key. Archived keys can't be changed.
+    pub fn set_key_enabled(
+        principal: &Principal,
+        key_id: &str,
+        enabled: bool,
+    ) -> Result<(), SignerError> {
+        State::update_key(principal, key_id, |record| {
+            if record.status == KeyStatus::Archived {
+                return Err(SignerError::KeyArchived);
+            }
+            record.status = match enabled {
+                true => KeyStatus::Active,
+                false => KeyStatus::Disabled,
+            };
+            Ok(())
+        })
+    }
+
+    /// Destroys the private key but keeps its metadata, so signatures made with it
+    /// can still be attributed.
+    pub fn archive_key(principal: &Principal, key_id: &str) -> Result<(), SignerError> {
+        State::update_key(principal, key_id, |record| {
+            record.privkey = None;
+            record.status = KeyStatus::Archived;
+            Ok(())
+        })
+    }
+
+    /// Records where a local key migrated to. The key keeps signing until
+    /// `destroy_migrated_key`.
+    pub fn deprecate_key(
+        principal: &Principal,
+        key_id: &str,
+        migration: &KeyMigration,
+    ) -> Result<(), SignerError> {
+        State::update_key(principal, key_id, |record| {
+            if record.status == KeyStatus::Archived {
+                return Err(SignerError::KeyArchived);
+            }
+            if record.migration.is_some() {
+                return Err(SignerError::InvalidArgument(
+                    "key is already migrated".to_string(),
+                ));
+            }
+            record.migration = Some(migration.clone());
+            Ok(())
+        })
+    }
+
+    /// Archives a migrated key once its grace period is over.
+    pub fn destroy_migrated_key(
+        principal: &Principal,
+        key_id: &str,
+        now: u64,
+    ) -> Result<(), SignerError> {
+        State::update_key(principal, key_id, |record| {
+            let destroy_after = match &record.migration {
+                Some(migration) => migration.destroy_after,
+                None => {
+                    return Err(SignerError::InvalidArgument(
+                        "key is not migrated".to_string(),
+                    ))
+                }
+            };
+            if now < destroy_after {
+                return Err(SignerError::InvalidArgument(format!(
+                    "key can't be destroyed before {}",
+                    destroy_after
+                )));
+            }
+            record.privkey = None;
+            record.status = KeyStatus::Archived;
+            Ok(())
+        })
+    }
+
+    pub fn delete_key(principal: &Principal, key_id: &str) -> Result<(), SignerError> {
+        let id = (stable_principal(principal), stable_key_id(key_id)?);
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let record = state.privkeys.remove(&id).ok_or(SignerError::KeyNotFound)?;
+            if let Some(alias) = record.alias {
+                state.aliases.remove(&(id.0, stable_alias(&alias)?));
+            }
+            Ok(())
+        })
+    }
+
+    /// Stores a private key in plaintext, as no key-encryption key can be generated
+    /// during an upgrade. It is sealed by the next `reseal_privkeys`, which waits for
+    /// a key-encryption key if there is none yet.
+    pub fn import_plain_privkey(
+        principal: &Principal,
+        key_id: &str,
+        key: &str,
+        now: u64,
+    ) -> Result<(), SignerError> {
+        let id = (stable_principal(principal), stable_key_id(key_id)?);
+        let publickey = ECDSAPrivateKey::from_string(key)?.to_pubkey()?;
+        let record = KeyRecord {
+            privkey: Some(StoredPrivkey::Plain(key.to_string())),
+            publickey,
+            created_at: now,
+            curve: Curve::Secp256k1,
+            hash_algorithms: HashAlgorithm::all(),
+            status: KeyStatus::Active,
+            alias: None,
+            description: None,
+            tags: Vec::new(),
+            migration: None,
+        };
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.privkeys.insert(id, record);
+            let kek = KeyEncryptionKey {
+                reseal_cursor: Some(ResealCursor::Start),
+                ..state.kek.get().clone()
+            };
+            state.kek.set(kek).map_err(|_| {
+                SignerError::Storage("Failed to save key-encryption key".to_string())
+            })?;
+            Ok(())
+        })
+    }
+
+    /// Imports the state saved by `storage::stable_save` before the key store
+    /// moved to stable structures. Must run before `STATE` is first touched, as
+    /// initializing the memory manager overwrites the old layout.
+    pub fn restore_legacy_layout() {
+        if api::stable::stable_size() == 0 {
+            return;
+        }
+        let mut magic = [0; 4];
+        api::stable::stable_read(0, &mut magic);
+        if &magic != b"DIDL" {
+            return;
+        }
+        let (stable_state,): (StableState,) =
+            storage::stable_restore().expect("Failed to restore state from stable memory");
+        let legacy = LegacyState::from(stable_state);
+
+        for (principal, pk_map) in &legacy.privkeys {
+            for (key_id, key) in pk_map {
+                State::import_plain_privkey(principal, key_id, key, api::time())
+                    .expect("Failed to migrate private key");
+            }
+        }
+        if !legacy.api_keys.is_empty() {
+            // `raw_rand` can't be called during an upgrade, but a salt only has to
+            // be unique, not secret
+            let seed = [api::id().as_slice(), &api::time().to_be_bytes()].concat();
+            State::init_apikey_salt(&salted_hash(b"apikey-salt", &seed))
+                .expect("Failed to migrate API keys");
+        }
+        for (principal, api_key) in &legacy.api_keys {
+            let info = ApiKeyInfo {
+                name: "default".to_string(),
+                scope: ApiKeyScope::default(),
+                created_at: api::time(),
+                expires_at: None,
+            };
+            State::add_apikey(principal, &info, api_key).expect("Failed to migrate API key");
+        }
+    }
+}
+
+fn seal_privkey(
+    kek: &mut KeyEncryptionKey,
+    id: &PrivkeyId,
+    privkey: &[u8],
+) -> Result<StoredPrivkey, SignerError> {
+    if kek.version == 0 {
+        return Err(SignerError::NoKeyEncryptionKey);
+    }
+    let mut nonce = Vec::with_capacity(NONCE_LEN);
+    nonce.extend_from_slice(&kek.version.to_be_bytes());
+    nonce.extend_from_slice(&kek.nonce_counter.to_be_bytes());
+    kek.nonce_counter += 1;
+
+    let ciphertext = seal(&kek.key, &nonce, &privkey_aad(id), privkey)?;
+    Ok(StoredPrivkey::Sealed {
+        kek_version: kek.version,
+        nonce,
+        ciphertext,
+    })
+}
+
+fn open_privkey(
+    kek: &KeyEncryptionKey,
+    id: &PrivkeyId,
+    privkey: &StoredPrivkey,
+) -> Result<Zeroizing<Vec<u8>>, SignerError> {
+    match privkey {
+        StoredPrivkey::Plain(key) => Ok(Zeroizing::new(hexstr_to_vec(key)?)),
+        StoredPrivkey::Sealed {
+            kek_version,
+            nonce,
+            ciphertext,
+        } => {
+            let key = kek.key(*kek_version).ok_or_else(|| {
+                SignerError::Crypto(
+                    "Private key is sealed under an unknown key-encryption key".to_string(),
+                )
+            })?;
+            Ok(Zeroizing::new(open(
+                key,
+                nonce,
+                &privkey_aad(id),
+                ciphertext,
+            )?))
+        }
+    }
+}
+
+/// Heap-resident state written to stable memory on upgrade by earlier releases.
+#[derive(CandidType, Deserialize, Default)]
+struct LegacyState {
+    privkeys: BTreeMap<Principal, BTreeMap<String, String>>,
+    api_keys: BTreeMap<Principal, String>,
+}
+
+/// Layouts of the heap-resident state saved by earlier releases. Only read when
+/// upgrading from them, see `State::restore_legacy_layout`.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(LegacyState),
+}
+
+impl From<StableState> for LegacyState {
+    fn from(stable_state: StableState) -> Self {
+        match stable_state {
+            StableState::V1(state) => state,
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_key_info(key_id: &str) -> KeyInfo {
+    KeyInfo {
+        key_id: key_id.to_string(),
+        publickey: vec![4; 65],
+        created_at: 0,
+        curve: Curve::Secp256k1,
+        hash_algorithms: vec![HashAlgorithm::Keccak256],
+        status: KeyStatus::Active,
+        alias: None,
+        description: None,
+        tags: Vec::new(),
+        migration: None,
+    }
+}
+
+#[cfg(test)]
+fn privkey_hex(
+    principal: &Principal,
+    key_id: &str,
+    hash_algo: HashAlgorithm,
+) -> Result<String, SignerError> {
+    State::get_privkey(principal, key_id, hash_algo).map(|key| key.to_string())
+}
+
+#[test]
+fn test_privkey_store() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 10]);
+    let keccak = HashAlgorithm::Keccak256;
+    assert!(State::set_privkey(&alice, &test_key_info("0"), &"aa".repeat(32)).is_err());
+
+    assert_eq!(State::rotate_kek(&[7; 32]).unwrap(), 1);
+    State::set_privkey(&alice, &test_key_info("0"), &"aa".repeat(32)).unwrap();
+    State::set_privkey(&alice, &test_key_info("1"), &"bb".repeat(32)).unwrap();
+    State::set_privkey(&bob, &test_key_info("0"), &"cc".repeat(32)).unwrap();
+    assert_eq!(
+        State::set_privkey(&alice, &test_key_info("1"), &"dd".repeat(32)),
+        Err(SignerError::KeyIdExists)
+    );
+
+    assert_eq!(privkey_hex(&alice, "1", keccak).unwrap(), "bb".repeat(32));
+    assert_eq!(privkey_hex(&bob, "0", keccak).unwrap(), "cc".repeat(32));
+    assert_eq!(
+        privkey_hex(&bob, "1", keccak),
+        Err(SignerError::KeyNotFound)
+    );
+
+    let kek_versions = || {
+        STATE.with(|state| {
+            state
+                .borrow()
+                .privkeys
+                .iter()
+                .map(|(_, record)| match record.privkey {
+                    Some(StoredPrivkey::Sealed { kek_version, .. }) => kek_version,
+                    _ => 0,
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(State::reseal_privkeys(3), Ok(true));
+    assert!(!State::reseal_pending());
+
+    // Keys sealed under a retired version still open while being re-sealed in batches
+    assert_eq!(State::rotate_kek(&[8; 32]).unwrap(), 2);
+    assert!(State::reseal_pending());
+    assert_eq!(privkey_hex(&alice, "0", keccak).unwrap(), "aa".repeat(32));
+    assert_eq!(State::reseal_privkeys(1), Ok(false));
+    assert_eq!(kek_versions(), vec![2, 1, 1]);
+    assert_eq!(privkey_hex(&alice, "1", keccak).unwrap(), "bb".repeat(32));
+
+    // Rotating again restarts the pass, both earlier versions are kept meanwhile
+    assert_eq!(State::rotate_kek(&[9; 32]).unwrap(), 3);
+    assert_eq!(State::reseal_privkeys(2), Ok(false));
+    assert_eq!(kek_versions(), vec![3, 3, 1]);
+    assert_eq!(privkey_hex(&bob, "0", keccak).unwrap(), "cc".repeat(32));
+    assert_eq!(State::reseal_privkeys(2), Ok(true));
+    assert_eq!(kek_versions(), vec![3, 3, 3]);
+    assert!(!State::reseal_pending());
+    STATE.with(|state| assert_eq!(state.borrow().kek.get().retired, None));
+    assert_eq!(privkey_hex(&alice, "0", keccak).unwrap(), "aa".repeat(32));
+    assert_eq!(privkey_hex(&bob, "0", keccak).unwrap(), "cc".repeat(32));
+}
+
+#[test]
+fn test_import_plain_privkey() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let keccak = HashAlgorithm::Keccak256;
+    let privkey = "6a73b985cfd0142ba4be36d8fc0654836509b419ad241161cc40dff62025a81d";
+    State::import_plain_privkey(&alice, "0", privkey, 0).unwrap();
+    assert_eq!(privkey_hex(&alice, "0", keccak).unwrap(), privkey);
+
+    // Sealed as soon as there is a key-encryption key
+    assert!(State::reseal_pending());
+    assert_eq!(
+        State::reseal_privkeys(10),
+        Err(SignerError::NoKeyEncryptionKey)
+    );
+    State::rotate_kek(&[7; 32]).unwrap();
+    assert_eq!(State::reseal_privkeys(10), Ok(true));
+    STATE.with(|state| {
+        let record = state.borrow().privkeys.iter().next().unwrap().1;
+        assert!(matches!(
+            record.privkey,
+            Some(StoredPrivkey::Sealed { kek_version: 1, .. })
+        ));
+    });
+    assert_eq!(privkey_hex(&alice, "0", keccak).unwrap(), privkey);
+}
+
+#[test]
+fn test_key_lifecycle() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let keccak = HashAlgorithm::Keccak256;
+    State::rotate_kek(&[7; 32]).unwrap();
+
+    for _ in 0..3 {
+        let key_id = State::allocate_key_id(&alice);
+        State::set_privkey(&alice, &test_key_info(&key_id), &"aa".repeat(32)).unwrap();
+    }
+    let key_ids: Vec<_> = State::list_keys(&alice)
+        .into_iter()
+        .map(|k| k.key_id)
+        .collect();
+    assert_eq!(key_ids, vec!["0", "1", "2"]);
+    assert_eq!(
+        privkey_hex(&alice, "0", HashAlgorithm::SHA3_256),
+        Err(SignerError::HashAlgorithmNotAllowed)
+    );
+
+    State::set_key_enabled(&alice, "0", false).unwrap();
+    assert_eq!(
+        State::get_key_info(&alice, "0").unwrap().status,
+        KeyStatus::Disabled
+    );
+    assert_eq!(
+        privkey_hex(&alice, "0", keccak),
+        Err(SignerError::KeyDisabled)
+    );
+    State::set_key_enabled(&alice, "0", true).unwrap();
+    assert_eq!(privkey_hex(&alice, "0", keccak).unwrap(), "aa".repeat(32));
+
+    State::archive_key(&alice, "1").unwrap();
+    assert_eq!(
+        privkey_hex(&alice, "1", keccak),
+        Err(SignerError::KeyArchived)
+    );
+    assert_eq!(
+        State::set_key_enabled(&alice, "1", true),
+        Err(SignerError::KeyArchived)
+    );
+    assert_eq!(
+        State::get_key_info(&alice, "1").unwrap().publickey,
+        vec![4; 65]
+    );
+
+    // Deleted IDs are not handed out again
+    State::delete_key(&alice, "2").unwrap();
+    assert_eq!(
+        State::delete_key(&alice, "2"),
+        Err(SignerError::KeyNotFound)
+    );
+    assert_eq!(State::allocate_key_id(&alice), "3");
+    assert_eq!(State::list_keys(&alice).len(), 2);
+}
+
+#[test]
+fn test_key_labels() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 10]);
+    State::rotate_kek(&[7; 32]).unwrap();
+
+    let mut info = test_key_info("0");
+    info.alias = Some("eth-main".to_string());
+    info.tags = vec!["eth".to_string(), "hot".to_string()];
+    State::set_privkey(&alice, &info, "aa").unwrap();
+    let mut info = test_key_info("1");
+    info.alias = Some("eth-main".to_string());
+    assert_eq!(
+        State::set_privkey(&alice, &info, "bb"),
+        Err(SignerError::AliasExists)
+    );
+    info.alias = Some("1".to_string());
+    assert!(matches!(
+        State::set_privkey(&alice, &info, "bb"),
+        Err(SignerError::InvalidAlias(_))
+    ));
+    info.alias = None;
+    info.tags = vec!["eth".to_string()];
+    State::set_privkey(&alice, &info, "bb").unwrap();
+
+    assert_eq!(State::resolve_key_id(&alice, "eth-main").unwrap(), "0");
+    assert_eq!(State::resolve_key_id(&alice, "1").unwrap(), "1");
+    assert_eq!(
+        State::resolve_key_id(&bob, "eth-main"),
+        Err(SignerError::KeyNotFound)
+    );
+    assert_eq!(State::find_keys_by_tag(&alice, "eth").len(), 2);
+    assert_eq!(State::find_keys_by_tag(&alice, "hot")[0].key_id, "0");
+
+    // The alias is released with its key
+    State::delete_key(&alice, "0").unwrap();
+    assert_eq!(
+        State::resolve_key_id(&alice, "eth-main"),
+        Err(SignerError::KeyNotFound)
+    );
+}
+
+#[test]
+fn test_apikeys() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 10]);
+    let info = |name: &str| ApiKeyInfo {
+        name: name.to_string(),
+        scope: ApiKeyScope::default(),
+        created_at: 0,
+        expires_at: None,
+    };
+
+    assert_eq!(
+        State::add_apikey(&alice, &info("key-1"), "k1"),
+        Err(SignerError::Storage("No API key salt".to_string()))
+    );
+    State::init_apikey_salt(b"salt").unwrap();
+    State::init_apikey_salt(b"another salt").unwrap();
+    assert!(State::has_apikey_salt());
+
+    assert_eq!(State::next_apikey_name(&alice), "key-1");
+    State::add_apikey(&alice, &info("key-1"), "k1").unwrap();
+    State::add_apikey(&alice, &info("ci"), "k2").unwrap();
+    State::add_apikey(&bob, &info("key-1"), "k3").unwrap();
+    assert_eq!(
+        State::add_apikey(&alice, &info("ci"), "k4"),
+        Err(SignerError::ApiKeyExists)
+    );
+    assert_eq!(State::next_apikey_name(&alice), "key-3");
+    assert_eq!(State::list_apikeys(&alice).len(), 2);
+
+    let (caller, api_key) = State::get_caller_by_apikey("k2").unwrap();
+    assert_eq!((caller, api_key.name.as_str()), (alice, "ci"));
+    assert_eq!(State::get_caller_by_apikey("k3").unwrap().0, bob);
+    assert!(State::get_caller_by_apikey("k4").is_none());
+    STATE.with(|state| {
+        let state = state.borrow();
+        let record = state
+            .api_keys
+            .get(&(stable_principal(&alice), stable_apikey_name("ci").unwrap()))
+            .unwrap();
+        assert_eq!(record.hash, salted_hash(b"salt", b"k2").to_vec());
+    });
+
+    State::revoke_apikey(&alice, "ci").unwrap();
+    assert!(State::get_caller_by_apikey("k2").is_none());
+    assert_eq!(
+        State::revoke_apikey(&alice, "ci"),
+        Err(SignerError::ApiKeyNotFound)
+    );
+    assert!(State::get_caller_by_apikey("k1").is_some());
+
+    let (caller, rotated) = State::rotate_apikey("k1", "k5", 100).unwrap();
+    assert_eq!((caller, rotated.name.as_str()), (alice, "key-1"));
+    assert_eq!(rotated.created_at, 100);
+    assert!(State::get_caller_by_apikey("k1").is_none());
+    assert_eq!(State::get_caller_by_apikey("k5").unwrap().1.name, "key-1");
+    // Nothing changes when either key is wrong
+    assert!(matches!(
+        State::rotate_apikey("k1", "k6", 200),
+        Err(SignerError::ApiKeyNotFound)
+    ));
+    assert!(matches!(
+        State::rotate_apikey("k5", "k3", 200),
+        Err(SignerError::ApiKeyExists)
+    ));
+    assert!(State::get_caller_by_apikey("k5").is_some());
+    assert_eq!(State::get_caller_by_apikey("k3").unwrap().0, bob);
+    assert_eq!(State::list_apikeys(&alice).len(), 1);
+}
+
+#[test]
+fn test_record_versions() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let record = ApiKeyRecord {
+        hash: vec![1; 32],
+        scope: ApiKeyScope::default(),
+        created_at: 7,
+        expires_at: None,
+    };
+    let bytes = record.to_bytes().into_owned();
+    assert_eq!(bytes[0], RECORD_VERSION);
+    let decoded = ApiKeyRecord::from_bytes(Cow::Borrowed(&bytes));
+    assert_eq!((decoded.hash, decoded.created_at), (vec![1; 32], 7));
+
+    // Records written before versioning are read as version 0
+    let legacy = Encode!(&Config {
+        owner: Some(alice),
+        ..Default::default()
+    })
+    .unwrap();
+    let decoded = Config::from_bytes(Cow::Owned(legacy));
+    assert_eq!(decoded.owner, Some(alice));
+
+    let newer = [&[RECORD_VERSION + 1][..], &bytes[1..]].concat();
+    let result = std::panic::catch_unwind(|| ApiKeyRecord::from_bytes(Cow::Owned(newer)));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_ecdsa_key_name() {
+    assert_eq!(State::ecdsa_key_name(), DEFAULT_ECDSA_KEY_NAME);
+    State::set_ecdsa_key_name("test_key_1").unwrap();
+    assert_eq!(State::ecdsa_key_name(), "test_key_1");
+    assert!(matches!(
+        State::set_ecdsa_key_name(""),
+        Err(SignerError::InvalidArgument(_))
+    ));
+    assert_eq!(State::ecdsa_key_name(), "test_key_1");
+}
+
+#[test]
+fn test_ic_public_key_cache() {
+    let key = IcPublicKey {
+        public_key: vec![2; 33],
+        chain_code: vec![7; 32],
+    };
+    let path = vec![vec![1; 29], vec![0, 1]];
+    assert_eq!(State::get_ic_public_key("key_1", &path), None);
+    State::cache_ic_public_key("key_1", &path, &key);
+    assert_eq!(State::get_ic_public_key("key_1", &path), Some(key.clone()));
+    // Keys of another name or path are distinct
+    assert_eq!(State::get_ic_public_key("test_key_1", &path), None);
+    assert_eq!(State::get_ic_public_key("key_1", &path[..1]), None);
+
+    // A principal only caches a bounded number of paths, others are not affected
+    for i in 1..IC_PUBLIC_KEYS_PER_PRINCIPAL as usize {
+        State::cache_ic_public_key("key_1", &[vec![1; 29], vec![0xee; i]], &key);
+    }
+    State::cache_ic_public_key("key_1", &path, &key);
+    let over_limit = vec![vec![1; 29], vec![0xff; 2]];
+    State::cache_ic_public_key("key_1", &over_limit, &key);
+    assert_eq!(State::get_ic_public_key("key_1", &over_limit), None);
+    let other = vec![vec![2; 29], vec![0xff; 2]];
+    State::cache_ic_public_key("key_1", &other, &key);
+    assert_eq!(State::get_ic_public_key("key_1", &other), Some(key));
+}
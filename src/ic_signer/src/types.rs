@@ -1,10 +1,26 @@
-use crate::crypto::Hash256;
-use crate::utils::{hexstr_to_vec, vec8_to_hexstr};
-use base64;
-use k256::ecdsa::{recoverable, signature::DigestSigner, SigningKey};
+use crate::crypto::{Hash256, Hasher256};
+use crate::error::SignerError;
+use crate::utils::{
+    hash_blake2b_256, hash_double_sha256, hash_keccak256, hash_sha256, hash_sha3_256,
+    hexstr_to_vec, vec8_to_hexstr,
+};
+use ic_cdk::export::candid::CandidType;
+use k256::ecdsa::{
+    recoverable,
+    signature::{
+        self,
+        digest::{FixedOutput, Update},
+        DigestSigner,
+    },
+    Signature, SigningKey,
+};
+use k256::{
+    elliptic_curve::sec1::ToEncodedPoint,
+    pkcs8::{EncodePublicKey, LineEnding},
+};
 use rand_core::{CryptoRng, Error, RngCore};
 use serde::{Deserialize, Serialize};
-use sha3::{Keccak256, Sha3_256};
+use zeroize::Zeroize;
 
 const ECDSA_PRIVKEY_LEN: usize = 32;
 
@@ -28,43 +44,374 @@ impl RngCore for OsRng<'_> {
     }
 
     fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
-        todo!()
+        self.fill_bytes(dest);
+        Ok(())
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub enum HashAlgorithm {
-    // SHA2_256,
+    /// Bitcoin, Cosmos
+    SHA2_256,
+    /// SHA-256 applied twice, Bitcoin
+    DoubleSHA2_256,
+    /// Filecoin
+    Blake2b256,
     SHA3_256,
+    /// Ethereum
+    #[default]
     Keccak256,
 }
 
-impl Default for HashAlgorithm {
-    fn default() -> Self {
-        HashAlgorithm::Keccak256
+// Evaluates `$body` with `$hasher` standing for the `Hasher256` of `$hash_algo`
+macro_rules! with_hasher {
+    ($hash_algo:expr, $hasher:ident => $body:expr) => {
+        match $hash_algo {
+            $crate::types::HashAlgorithm::SHA2_256 => {
+                type $hasher = sha2::Sha256;
+                $body
+            }
+            $crate::types::HashAlgorithm::DoubleSHA2_256 => {
+                type $hasher = $crate::crypto::DoubleSha256;
+                $body
+            }
+            $crate::types::HashAlgorithm::Blake2b256 => {
+                type $hasher = $crate::crypto::Blake2b256;
+                $body
+            }
+            $crate::types::HashAlgorithm::SHA3_256 => {
+                type $hasher = sha3::Sha3_256;
+                $body
+            }
+            $crate::types::HashAlgorithm::Keccak256 => {
+                type $hasher = sha3::Keccak256;
+                $body
+            }
+        }
+    };
+}
+
+pub(crate) use with_hasher;
+
+impl HashAlgorithm {
+    pub fn all() -> Vec<HashAlgorithm> {
+        vec![
+            HashAlgorithm::SHA2_256,
+            HashAlgorithm::DoubleSHA2_256,
+            HashAlgorithm::Blake2b256,
+            HashAlgorithm::SHA3_256,
+            HashAlgorithm::Keccak256,
+        ]
+    }
+
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::SHA2_256 => hash_sha256(data),
+            HashAlgorithm::DoubleSHA2_256 => hash_double_sha256(data),
+            HashAlgorithm::Blake2b256 => hash_blake2b_256(data),
+            HashAlgorithm::SHA3_256 => hash_sha3_256(data),
+            HashAlgorithm::Keccak256 => hash_keccak256(data),
+        }
+    }
+}
+
+/// How a message passed as text is encoded
+#[derive(CandidType, Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MessageEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+impl MessageEncoding {
+    pub fn decode(&self, text: &str) -> Result<Vec<u8>, SignerError> {
+        match self {
+            MessageEncoding::Hex => hexstr_to_vec(text),
+            MessageEncoding::Base64 => base64::decode(text).map_err(|_| SignerError::InvalidBase64),
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> String {
+        match self {
+            MessageEncoding::Hex => hex::encode(data),
+            MessageEncoding::Base64 => base64::encode(data),
+        }
+    }
+}
+
+/// Layout of a signature, `r` and `s` are 32 bytes big-endian
+#[derive(CandidType, Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SignatureFormat {
+    /// r||s||v with v in 0/1
+    #[default]
+    Recoverable,
+    /// r||s
+    Compact,
+    /// r||s||v with v in 27/28
+    Ethereum,
+    /// r||s||v with v = chain_id * 2 + 35 + recovery id, big-endian
+    EIP155(u64),
+    /// r||s with the recovery id in the top bit of s
+    EIP2098,
+    /// ASN.1 DER
+    Der,
+}
+
+impl SignatureFormat {
+    // `signature` is r||s||v with v in 0/1, or r||s when the recovery id is unknown
+    pub fn apply(&self, signature: &[u8]) -> Result<Vec<u8>, SignerError> {
+        if signature.len() != 64 && signature.len() != 65 {
+            return Err(SignerError::InvalidLength("signature".to_string()));
+        }
+        let rs = &signature[..64];
+        let recovery_id = || match signature.get(64) {
+            Some(&v) if v < 2 => Ok(v),
+            Some(_) => Err(SignerError::Crypto("Invalid recovery id".to_string())),
+            None => Err(SignerError::Crypto(
+                "The recovery id of the signature is unknown".to_string(),
+            )),
+        };
+        let formatted = match self {
+            SignatureFormat::Recoverable => [rs, &[recovery_id()?]].concat(),
+            SignatureFormat::Compact => rs.to_vec(),
+            SignatureFormat::Ethereum => [rs, &[recovery_id()? + 27]].concat(),
+            SignatureFormat::EIP155(chain_id) => {
+                let recovery_id = recovery_id()? as u64;
+                let v = chain_id
+                    .checked_mul(2)
+                    .and_then(|v| v.checked_add(35 + recovery_id))
+                    .ok_or_else(|| SignerError::Crypto("Chain ID is too large".to_string()))?
+                    .to_be_bytes();
+                let start = v.iter().position(|&b| b != 0).unwrap_or(v.len() - 1);
+                [rs, &v[start..]].concat()
+            }
+            SignatureFormat::EIP2098 => {
+                let mut compact = rs.to_vec();
+                compact[32] |= recovery_id()? << 7;
+                compact
+            }
+            SignatureFormat::Der => {
+                let signature = <Signature as signature::Signature>::from_bytes(rs)
+                    .map_err(|_| SignerError::Crypto("Malformed signature".to_string()))?;
+                signature.to_der().as_ref().to_vec()
+            }
+        };
+        Ok(formatted)
     }
 }
 
 pub trait PrivateKey {
     fn to_string(&self) -> String;
-    fn to_vec8(&self) -> Vec<u8>;
-    fn len(&self) -> usize;
-    fn sign(&self, msg_hash: &Vec<u8>, hash_algo: HashAlgorithm) -> Result<Vec<u8>, String>;
-    fn to_pubkey(&self) -> Result<Vec<u8>, String>;
+    fn sign(&self, msg_hash: &[u8], hash_algo: HashAlgorithm) -> Result<Vec<u8>, SignerError>;
+    /// Hashes `message` with `hash_algo` and signs it, returns the digest and the signature
+    fn sign_message(
+        &self,
+        message: &[u8],
+        hash_algo: HashAlgorithm,
+    ) -> Result<(Vec<u8>, Vec<u8>), SignerError>;
+    fn to_pubkey(&self) -> Result<Vec<u8>, SignerError>;
 }
 
 pub trait PublicKey {
-    fn to_string(&self) -> String;
-    fn from_vec8(&self, data: &Vec<u8>) -> ();
-    fn to_vec8(&self) -> String;
-    fn len(&self) -> usize;
+    fn to_vec8(&self) -> Vec<u8>;
+    fn encode(&self, encoding: PublicKeyEncoding) -> Result<EncodedPublicKey, SignerError>;
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(CandidType, Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PublicKeyEncoding {
+    /// 33-byte SEC1
+    Compressed,
+    /// 65-byte SEC1
+    #[default]
+    Uncompressed,
+    /// 32-byte x coordinate, as used by BIP-340
+    XOnly,
+    /// DER encoded SubjectPublicKeyInfo
+    SpkiDer,
+    /// PEM encoded SubjectPublicKeyInfo
+    Pem,
+    Jwk,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum EncodedPublicKey {
+    Binary(Vec<u8>),
+    Text(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
 pub struct Bundle {
     pub digest: Vec<u8>,
     pub publickey: Vec<u8>,
     pub signature: Vec<u8>,
+    /// The hash algorithm the digest was computed with
+    pub hash_algorithm: HashAlgorithm,
+    pub signature_format: SignatureFormat,
+}
+
+impl Bundle {
+    // Bundles are made with r||s||v signatures, or r||s for threshold ECDSA
+    pub fn with_format(self, format: SignatureFormat) -> Result<Bundle, SignerError> {
+        Ok(Bundle {
+            signature: format.apply(&self.signature)?,
+            signature_format: format,
+            ..self
+        })
+    }
+}
+
+/// A threshold ECDSA public key as returned by the management canister, `public_key` is
+/// compressed SEC1
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct IcPublicKey {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+/// The outcome of a signature check, `reason` tells why it failed
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub valid: bool,
+    pub reason: Option<String>,
+}
+
+impl From<Result<(), String>> for Verification {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Verification {
+                valid: true,
+                reason: None,
+            },
+            Err(reason) => Verification {
+                valid: false,
+                reason: Some(reason),
+            },
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BitcoinNetwork {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+/// The kind of address to derive from a public key
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum AddressType {
+    /// EIP-55 checksummed
+    Ethereum,
+    BitcoinP2PKH(BitcoinNetwork),
+    BitcoinP2WPKH(BitcoinNetwork),
+    /// Key path only, as in BIP-86
+    BitcoinP2TR(BitcoinNetwork),
+    /// Bech32 with the given human-readable part, `cosmos` by default
+    Cosmos(Option<String>),
+    Tron,
+    /// `PUB_K1_` public key string
+    Eos,
+    /// `EOS` prefixed public key string
+    EosLegacy,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Curve {
+    Secp256k1,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyStatus {
+    Active,
+    /// Migrated to a threshold ECDSA key, still signs until the local key is destroyed
+    Deprecated,
+    /// Can't sign until enabled again
+    Disabled,
+    /// The private key has been destroyed, only the metadata is kept
+    Archived,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KeyInfo {
+    pub key_id: String,
+    pub publickey: Vec<u8>,
+    /// Nanoseconds since the UNIX epoch
+    pub created_at: u64,
+    pub curve: Curve,
+    /// Hash algorithms the key may sign digests of
+    pub hash_algorithms: Vec<HashAlgorithm>,
+    pub status: KeyStatus,
+    /// Unique among the keys of the owner, can be used in place of the key ID
+    pub alias: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Set once the key is deprecated in favour of a threshold ECDSA key
+    pub migration: Option<KeyMigration>,
+}
+
+/// The threshold ECDSA key a local key was migrated to. The local key keeps signing
+/// until it is destroyed, which is allowed from `destroy_after` on.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyMigration {
+    /// Sub-path of the threshold key of the owner, as passed to `sign`
+    pub derivation_path: Vec<Vec<u8>>,
+    /// Uncompressed SEC1
+    pub publickey: Vec<u8>,
+    /// Nanoseconds since the UNIX epoch
+    pub deprecated_at: u64,
+    /// Nanoseconds since the UNIX epoch
+    pub destroy_after: u64,
+}
+
+/// What an API key may be used for, `None` places no restriction.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApiKeyScope {
+    /// Stored keys, an API key limited to some of them can't sign with threshold keys
+    pub key_ids: Option<Vec<String>>,
+    /// JSON-RPC methods, compared case-insensitively
+    pub methods: Option<Vec<String>>,
+    pub hash_algorithms: Option<Vec<HashAlgorithm>>,
+}
+
+impl ApiKeyScope {
+    pub fn allows_key(&self, key_id: &str) -> bool {
+        self.key_ids
+            .as_ref()
+            .is_none_or(|ids| ids.iter().any(|id| id == key_id))
+    }
+
+    // Threshold keys have no key ID to be listed in `key_ids`
+    pub fn allows_threshold_keys(&self) -> bool {
+        self.key_ids.is_none()
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .as_ref()
+            .is_none_or(|methods| methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+    }
+
+    pub fn allows_hash_algorithm(&self, hash_algo: HashAlgorithm) -> bool {
+        self.hash_algorithms
+            .as_ref()
+            .is_none_or(|algos| algos.contains(&hash_algo))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApiKeyInfo {
+    /// Unique among the API keys of the owner
+    pub name: String,
+    pub scope: ApiKeyScope,
+    /// Nanoseconds since the UNIX epoch
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl ApiKeyInfo {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
 }
 
 #[derive(Debug)]
@@ -79,9 +426,9 @@ impl ECDSAPrivateKey {
         ECDSAPrivateKey { data }
     }
 
-    pub fn from_string(hex_string: &str) -> Result<ECDSAPrivateKey, String> {
+    pub fn from_string(hex_string: &str) -> Result<ECDSAPrivateKey, SignerError> {
         if hex_string.len() != ECDSA_PRIVKEY_LEN * 2 {
-            return Err("ECDSA private key string's length error".to_string());
+            return Err(SignerError::InvalidLength("ECDSA private key".to_string()));
         }
         match hexstr_to_vec(hex_string) {
             Ok(res) => {
@@ -92,80 +439,137 @@ impl ECDSAPrivateKey {
         }
     }
 
-    pub fn from_vec8(vec: &Vec<u8>) -> Result<ECDSAPrivateKey, String> {
+    pub fn from_vec8(vec: &[u8]) -> Result<ECDSAPrivateKey, SignerError> {
         if vec.len() != ECDSA_PRIVKEY_LEN {
-            return Err("ECDSA private key string's length error".to_string());
+            return Err(SignerError::InvalidLength("ECDSA private key".to_string()));
         }
-        let key = ECDSAPrivateKey { data: vec.clone() };
+        let key = ECDSAPrivateKey { data: vec.to_vec() };
         Ok(key)
     }
 }
 
+impl Zeroize for ECDSAPrivateKey {
+    fn zeroize(&mut self) {
+        self.data.zeroize();
+    }
+}
+
 impl PrivateKey for ECDSAPrivateKey {
     fn to_string(&self) -> String {
         vec8_to_hexstr(&self.data)
     }
 
-    fn to_vec8(&self) -> Vec<u8> {
-        self.data.clone()
-    }
-
-    fn len(&self) -> usize {
-        self.data.len()
-    }
-
-    fn sign(&self, msg_hash: &Vec<u8>, hash_algo: HashAlgorithm) -> Result<Vec<u8>, String> {
+    fn sign(&self, msg_hash: &[u8], hash_algo: HashAlgorithm) -> Result<Vec<u8>, SignerError> {
         let signing_key = match SigningKey::from_bytes(&self.data) {
             Ok(key) => key,
-            Err(_) => return Err("Get signing key failed".to_string()),
-        };
-        // let rsv: recoverable::Signature = signing_key.sign(&message_u8);
-        type HashSha3_256 = Hash256<Sha3_256>;
-        type HashKeccak256 = Hash256<Keccak256>;
-        let rsv: recoverable::Signature = match hash_algo {
-            HashAlgorithm::SHA3_256 => {
-                let digest = HashSha3_256::try_from(msg_hash.as_ref())?;
-                DigestSigner::sign_digest(&signing_key, digest)
-            }
-            HashAlgorithm::Keccak256 => {
-                let digest = HashKeccak256::try_from(msg_hash.as_ref())?;
-                DigestSigner::sign_digest(&signing_key, digest)
+            Err(_) => {
+                return Err(SignerError::InvalidKey(
+                    "Get signing key failed".to_string(),
+                ))
             }
         };
-        // let signature: Vec<u8> = rsv.as_ref()[..64].to_vec();
+        let rsv: recoverable::Signature = with_hasher!(hash_algo, H => {
+            let digest = Hash256::<H>::try_from(msg_hash)?;
+            DigestSigner::sign_digest(&signing_key, digest)
+        });
         let signature: Vec<u8> = rsv.as_ref().to_vec();
-
-        // let id: u8 = rsv.recovery_id().into();
-        // println!("signature: {}", vec8_to_hexstr(&rsv.as_ref().to_vec()));
-        // println!("id: {}", id);
         Ok(signature)
     }
 
-    fn to_pubkey(&self) -> Result<Vec<u8>, String> {
+    fn sign_message(
+        &self,
+        message: &[u8],
+        hash_algo: HashAlgorithm,
+    ) -> Result<(Vec<u8>, Vec<u8>), SignerError> {
         let signing_key = match SigningKey::from_bytes(&self.data) {
             Ok(key) => key,
-            Err(_) => return Err("Get signing key failed".to_string()),
+            Err(_) => {
+                return Err(SignerError::InvalidKey(
+                    "Get signing key failed".to_string(),
+                ))
+            }
         };
-        let verify_key = signing_key.verifying_key();
-        let pubkey_info = k256::PublicKey::from(verify_key).to_string();
-        let end = pubkey_info.len() - 24 - 2; // remove -----END PUBLIC KEY-----
-        let pubkey_str = &pubkey_info[27..end].replace("\n", ""); // remove -----BEGIN PUBLIC KEY-----
-        let pubkey = match base64::decode(pubkey_str) {
-            Ok(bytes) => bytes,
-            Err(_) => return Err("Decode pubkey_str failed".to_string()),
+        fn sign_with<A: Hasher256 + Default + Clone>(
+            signing_key: &SigningKey,
+            message: &[u8],
+        ) -> (Vec<u8>, recoverable::Signature) {
+            let mut hasher = Hash256::<A>::default();
+            Update::update(&mut hasher, message);
+            let digest = hasher.clone().finalize_fixed().to_vec();
+            (digest, DigestSigner::sign_digest(signing_key, hasher))
+        }
+        let (digest, rsv) = with_hasher!(hash_algo, H => sign_with::<H>(&signing_key, message));
+        Ok((digest, rsv.as_ref().to_vec()))
+    }
+
+    fn to_pubkey(&self) -> Result<Vec<u8>, SignerError> {
+        let signing_key = match SigningKey::from_bytes(&self.data) {
+            Ok(key) => key,
+            Err(_) => {
+                return Err(SignerError::InvalidKey(
+                    "Get signing key failed".to_string(),
+                ))
+            }
         };
-        // println!("pubkey: {:?}", vec8_to_hexstr(&pubkey[..23].to_vec()));
-        // println!("pubkey: {:?}", vec8_to_hexstr(&pubkey[23..].to_vec()));
-        Ok(pubkey[23..].to_vec())
+        let pubkey = ECDSAPublicKey {
+            key: k256::PublicKey::from(signing_key.verifying_key()),
+        };
+        Ok(pubkey.to_vec8())
     }
 }
-// MFYwEAYHKoZIzj0CAQYFK4EEAAoDQgAEWn83kD4nNdAJEhVemPEwJeCwldjT/bhCW5gbK2+9TApxBBXxu40HwMEZP/jrOYr4Dhuat8PnkISyo41zoOd0Vg==
-// 045A7F37903E2735D00912155E98F13025E0B095D8D3FDB8425B981B2B6FBD4C0A710415F1BB8D07C0C1193FF8EB398AF80E1B9AB7C3E79084B2A38D73A0E77456
-// 3056301006072a8648ce3d020106052b8104000a034200045a7f37903e2735d00912155e98f13025e0b095d8d3fdb8425b981b2b6fbd4c0a710415f1bb8d07c0c1193ff8eb398af80e1b9ab7c3e79084b2a38d73a0e77456
 
-// #[derive(Debug)]
-// struct ECDSAPublicKey {
-//     pub canister_id: Option<CanisterId>,
-//     pub derivation_path: Vec<Vec<u8>>,
-//     pub key_id: EcdsaKeyId,
-// }
+#[derive(Debug)]
+pub struct ECDSAPublicKey {
+    key: k256::PublicKey,
+}
+
+impl ECDSAPublicKey {
+    // Takes compressed or uncompressed SEC1
+    pub fn from_vec8(vec: &[u8]) -> Result<ECDSAPublicKey, SignerError> {
+        let key = k256::PublicKey::from_sec1_bytes(vec)
+            .map_err(|_| SignerError::InvalidKey("Not a secp256k1 public key".to_string()))?;
+        Ok(ECDSAPublicKey { key })
+    }
+
+    fn sec1(&self, compress: bool) -> Vec<u8> {
+        self.key.to_encoded_point(compress).as_bytes().to_vec()
+    }
+}
+
+impl PublicKey for ECDSAPublicKey {
+    // Uncompressed SEC1
+    fn to_vec8(&self) -> Vec<u8> {
+        self.sec1(false)
+    }
+
+    fn encode(&self, encoding: PublicKeyEncoding) -> Result<EncodedPublicKey, SignerError> {
+        let spki_error = |_| SignerError::Crypto("Encode SubjectPublicKeyInfo failed".to_string());
+        let encoded = match encoding {
+            PublicKeyEncoding::Compressed => EncodedPublicKey::Binary(self.sec1(true)),
+            PublicKeyEncoding::Uncompressed => EncodedPublicKey::Binary(self.sec1(false)),
+            PublicKeyEncoding::XOnly => EncodedPublicKey::Binary(self.sec1(true)[1..].to_vec()),
+            PublicKeyEncoding::SpkiDer => {
+                let der = self.key.to_public_key_der().map_err(spki_error)?;
+                EncodedPublicKey::Binary(der.as_ref().to_vec())
+            }
+            PublicKeyEncoding::Pem => EncodedPublicKey::Text(
+                self.key
+                    .to_public_key_pem(LineEnding::LF)
+                    .map_err(spki_error)?,
+            ),
+            PublicKeyEncoding::Jwk => {
+                let point = self.sec1(false);
+                let coordinate =
+                    |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+                let jwk = serde_json::json!({
+                    "kty": "EC",
+                    "crv": "secp256k1",
+                    "x": coordinate(&point[1..33]),
+                    "y": coordinate(&point[33..]),
+                });
+                EncodedPublicKey::Text(jwk.to_string())
+            }
+        };
+        Ok(encoded)
+    }
+}
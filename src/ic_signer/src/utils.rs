@@ -1,12 +1,19 @@
-use crate::crypto::Hash256;
+use crate::crypto::{Blake2b256, DoubleSha256, Hash256, Hasher256};
+use crate::error::SignerError;
+use crate::types::{with_hasher, HashAlgorithm};
 use hex::FromHex;
-use k256::ecdsa::{signature, signature::DigestVerifier, Signature, VerifyingKey};
-use sha3::{Digest, Keccak256, Sha3_256};
+use k256::{
+    ecdsa::{recoverable, signature, signature::DigestVerifier, Signature, VerifyingKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+    FieldBytes,
+};
+use sha2::Sha256;
+use sha3::{Keccak256, Sha3_256};
 
-pub fn hexstr_to_vec(text: &str) -> Result<Vec<u8>, String> {
+pub fn hexstr_to_vec(text: &str) -> Result<Vec<u8>, SignerError> {
     let data = match Vec::from_hex(text) {
         Ok(vec) => vec,
-        Err(_) => return Err("Failed to encode from hex string".to_string()),
+        Err(_) => return Err(SignerError::InvalidHex),
     };
 
     let vec: Vec<u8> = data;
@@ -17,39 +24,140 @@ pub fn vec8_to_hexstr(data: &Vec<u8>) -> String {
     hex::encode(data)
 }
 
-pub fn hash_sha256(data: &Vec<u8>) -> Vec<u8> {
-    let mut hasher = Sha3_256::new();
-
-    // write input message
-    hasher.update(data);
+pub fn hash_sha256(data: &[u8]) -> Vec<u8> {
+    <Sha256 as Hasher256>::hash(data).to_vec()
+}
 
-    // read hash digest
-    hasher.finalize().to_vec()
+pub fn hash_double_sha256(data: &[u8]) -> Vec<u8> {
+    <DoubleSha256 as Hasher256>::hash(data).to_vec()
 }
 
-pub fn hash_keccak256(data: &Vec<u8>) -> Vec<u8> {
-    let mut hasher = Keccak256::new();
+pub fn hash_blake2b_256(data: &[u8]) -> Vec<u8> {
+    <Blake2b256 as Hasher256>::hash(data).to_vec()
+}
 
-    // write input message
-    hasher.update(data);
+pub fn hash_sha3_256(data: &[u8]) -> Vec<u8> {
+    <Sha3_256 as Hasher256>::hash(data).to_vec()
+}
 
-    // read hash digest
-    hasher.finalize().to_vec()
+pub fn hash_keccak256(data: &[u8]) -> Vec<u8> {
+    <Keccak256 as Hasher256>::hash(data).to_vec()
 }
 
+// Malformed input fails the verification instead of trapping
 pub fn verify_signature(
     msg_hash: &[u8],
     sig_bytes: &[u8],
     pubkey_bytes: &[u8],
+    hash_algo: HashAlgorithm,
 ) -> bool {
-    // let message_hash = hash_keccak256(&message.to_vec());
-    let mut sigbytes_64: [u8; 64] = [0; 64];
-    sigbytes_64.copy_from_slice(&sig_bytes[..64]);
-    let signature: Signature = signature::Signature::from_bytes(&sigbytes_64)
-        .expect("Response is not a valid signature");
+    check_signature(msg_hash, sig_bytes, pubkey_bytes, hash_algo).is_ok()
+}
+
+// Like `verify_signature`, tells why the signature didn't verify
+pub fn check_signature(
+    msg_hash: &[u8],
+    sig_bytes: &[u8],
+    pubkey_bytes: &[u8],
+    hash_algo: HashAlgorithm,
+) -> Result<(), String> {
+    let signature = parse_signature(sig_bytes)?;
     let verifying_key = VerifyingKey::from_sec1_bytes(pubkey_bytes)
-        .expect("Response is not a valid public key");
-    let digest = Hash256::<Sha3_256>::try_from(msg_hash.as_ref())
-        .expect("Message is not a valid SHA256");
-    verifying_key.verify_digest(digest, &signature).is_ok()
-}
\ No newline at end of file
+        .map_err(|_| "public key is not a SEC1 encoded secp256k1 point".to_string())?;
+    with_hasher!(hash_algo, H => {
+        let digest = Hash256::<H>::try_from(msg_hash)
+            .map_err(|_| format!("digest is {} bytes, expected 32", msg_hash.len()))?;
+        verifying_key
+            .verify_digest(digest, &signature)
+            .map_err(|_| "signature does not match the digest and public key".to_string())
+    })
+}
+
+// Takes r||s, r||s||v or ASN.1 DER. v is 0/1, 27/28 or EIP-155 (chain_id * 2 + 35/36,
+// big-endian), DER is tried first for the lengths both may have. Malleable signatures
+// with a high s are rejected, so an r||s with the top bit of s set can only be EIP-2098,
+// where that bit is the y parity.
+fn parse_signature(sig_bytes: &[u8]) -> Result<Signature, String> {
+    let from_bytes = |rs: &[u8]| {
+        <Signature as signature::Signature>::from_bytes(rs)
+            .map_err(|_| "r or s is out of range".to_string())
+    };
+    let signature = match sig_bytes.len() {
+        64 if sig_bytes[32] & 0x80 != 0 => {
+            let mut rs = sig_bytes.to_vec();
+            rs[32] &= 0x7f;
+            from_bytes(&rs)?
+        }
+        64 => from_bytes(sig_bytes)?,
+        65..=72 => match Signature::from_der(sig_bytes) {
+            Ok(signature) if sig_bytes.len() > 65 => signature,
+            _ => {
+                check_recovery_id(&sig_bytes[64..])?;
+                from_bytes(&sig_bytes[..64])?
+            }
+        },
+        len => Signature::from_der(sig_bytes)
+            .map_err(|_| format!("malformed signature of {} bytes", len))?,
+    };
+    if signature.normalize_s().is_some() {
+        return Err(
+            "signature is not canonical, s is higher than half the curve order".to_string(),
+        );
+    }
+    Ok(signature)
+}
+
+// Up to 8 bytes, as `SignatureFormat::EIP155` takes a 64-bit chain ID
+fn check_recovery_id(v: &[u8]) -> Result<(), String> {
+    match v {
+        [0 | 1 | 27 | 28] | [35..=255] => Ok(()),
+        [first, _, ..] if *first != 0 => Ok(()),
+        _ => Err(format!("invalid recovery id {}", hex::encode(v))),
+    }
+}
+
+// Takes r||s||v with v in 0/1 or 27/28, returns the uncompressed SEC1 public key
+pub fn recover_pubkey(msg_hash: &[u8], sig_bytes: &[u8]) -> Result<Vec<u8>, SignerError> {
+    if msg_hash.len() != 32 {
+        return Err(SignerError::InvalidLength("message hash".to_string()));
+    }
+    if sig_bytes.len() != 65 {
+        return Err(SignerError::InvalidLength(
+            "recoverable signature".to_string(),
+        ));
+    }
+    let recovery_id = match sig_bytes[64] {
+        v @ (27 | 28) => v - 27,
+        v => v,
+    };
+    let recovery_id = recoverable::Id::new(recovery_id)
+        .map_err(|_| SignerError::Crypto("Invalid recovery id".to_string()))?;
+    let signature = <Signature as signature::Signature>::from_bytes(&sig_bytes[..64])
+        .map_err(|_| SignerError::Crypto("Malformed signature".to_string()))?;
+    let verifying_key = recoverable::Signature::new(&signature, recovery_id)
+        .and_then(|rsv| rsv.recover_verify_key_from_digest_bytes(FieldBytes::from_slice(msg_hash)))
+        .map_err(|_| SignerError::Crypto("Failed to recover the public key".to_string()))?;
+    let pubkey = verifying_key.to_encoded_point(false).as_bytes().to_vec();
+    // Recovery doesn't check the signature by itself
+    if !verify_signature(msg_hash, sig_bytes, &pubkey, HashAlgorithm::Keccak256) {
+        return Err(SignerError::VerificationFailed);
+    }
+    Ok(pubkey)
+}
+
+// Threshold ECDSA signatures are r||s, the recovery id is found by recovering with each
+// one until `pubkey` comes out. Returns r||s||v with a low s.
+pub fn make_recoverable(
+    msg_hash: &[u8],
+    sig_bytes: &[u8],
+    pubkey: &[u8],
+) -> Result<Vec<u8>, SignerError> {
+    let signature = <Signature as signature::Signature>::from_bytes(sig_bytes)
+        .map_err(|_| SignerError::Crypto("Malformed signature".to_string()))?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let rs = signature.as_ref().to_vec();
+    (0..2)
+        .map(|v| [&rs[..], &[v]].concat())
+        .find(|rsv| recover_pubkey(msg_hash, rsv).is_ok_and(|key| key == pubkey))
+        .ok_or(SignerError::VerificationFailed)
+}
@@ -1,5 +1,84 @@
 export const idlFactory = ({ IDL }) => {
-  const privkey_gen_res = IDL.Tuple(IDL.Text, IDL.Text);
+  const SignerError = IDL.Variant({
+    'InvalidHex' : IDL.Null,
+    'InvalidBase64' : IDL.Null,
+    'InvalidLength' : IDL.Text,
+    'InvalidKey' : IDL.Text,
+    'InvalidArgument' : IDL.Text,
+    'KeyNotFound' : IDL.Null,
+    'KeyIdExists' : IDL.Null,
+    'AliasExists' : IDL.Null,
+    'InvalidAlias' : IDL.Text,
+    'KeyDisabled' : IDL.Null,
+    'KeyArchived' : IDL.Null,
+    'HashAlgorithmNotAllowed' : IDL.Null,
+    'ApiKeyNotFound' : IDL.Null,
+    'ApiKeyExists' : IDL.Null,
+    'ApiKeyExpired' : IDL.Null,
+    'OutOfApiKeyScope' : IDL.Text,
+    'Unauthorized' : IDL.Null,
+    'RawPrivkeyDisabled' : IDL.Null,
+    'NoKeyEncryptionKey' : IDL.Null,
+    'Crypto' : IDL.Text,
+    'VerificationFailed' : IDL.Null,
+    'ManagementCanister' : IDL.Text,
+    'Storage' : IDL.Text,
+  });
+  const HashAlgorithm = IDL.Variant({
+    'SHA2_256' : IDL.Null,
+    'DoubleSHA2_256' : IDL.Null,
+    'Blake2b256' : IDL.Null,
+    'SHA3_256' : IDL.Null,
+    'Keccak256' : IDL.Null,
+  });
+  const SignatureFormat = IDL.Variant({
+    'Recoverable' : IDL.Null,
+    'Compact' : IDL.Null,
+    'Ethereum' : IDL.Null,
+    'EIP155' : IDL.Nat64,
+    'EIP2098' : IDL.Null,
+    'Der' : IDL.Null,
+  });
+  const ApiKeyScope = IDL.Record({
+    'key_ids' : IDL.Opt(IDL.Vec(IDL.Text)),
+    'methods' : IDL.Opt(IDL.Vec(IDL.Text)),
+    'hash_algorithms' : IDL.Opt(IDL.Vec(HashAlgorithm)),
+  });
+  const apikey_gen_args = IDL.Record({
+    'name' : IDL.Opt(IDL.Text),
+    'scope' : IDL.Opt(ApiKeyScope),
+    'expires_at' : IDL.Opt(IDL.Nat64),
+  });
+  const apikey_gen_res = IDL.Record({
+    'name' : IDL.Text,
+    'api_key' : IDL.Text,
+  });
+  const apikey_gen_result = IDL.Variant({
+    'Ok' : apikey_gen_res,
+    'Err' : SignerError,
+  });
+  const privkey_gen_args = IDL.Record({
+    'alias' : IDL.Opt(IDL.Text),
+    'description' : IDL.Opt(IDL.Text),
+    'tags' : IDL.Vec(IDL.Text),
+  });
+  const privkey_gen_res = IDL.Record({
+    'key_id' : IDL.Text,
+    'publickey' : IDL.Vec(IDL.Nat8),
+  });
+  const privkey_gen_result = IDL.Variant({
+    'Ok' : privkey_gen_res,
+    'Err' : SignerError,
+  });
+  const rotate_kek_result = IDL.Variant({ 'Ok' : IDL.Nat32, 'Err' : SignerError });
+  const SignatureBundle = IDL.Record({
+    'hash_algorithm' : HashAlgorithm,
+    'signature_format' : SignatureFormat,
+    'signature' : IDL.Vec(IDL.Nat8),
+    'publickey' : IDL.Vec(IDL.Nat8),
+    'digest' : IDL.Vec(IDL.Nat8),
+  });
+  const sign_result = IDL.Variant({ 'Ok' : SignatureBundle, 'Err' : SignerError });
   const http_header = IDL.Tuple(IDL.Text, IDL.Text);
   const strategy = IDL.Variant({
     'Callback' : IDL.Record({
@@ -7,6 +86,12 @@ export const idlFactory = ({ IDL }) => {
       'callback' : IDL.Func([], [], []),
     }),
   });
+  const http_request = IDL.Record({
+    'url' : IDL.Text,
+    'method' : IDL.Text,
+    'body' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'headers' : IDL.Vec(http_header),
+  });
   const http_response = IDL.Record({
     'body' : IDL.Vec(IDL.Nat8),
     'headers' : IDL.Vec(http_header),
@@ -15,22 +100,40 @@ export const idlFactory = ({ IDL }) => {
     'status_code' : IDL.Nat16,
   });
   return IDL.Service({
-    'generate_apikey' : IDL.Func([], [IDL.Text], []),
-    'generate_privkey' : IDL.Func([], [privkey_gen_res], []),
-    'http_request' : IDL.Func(
+    'generate_apikey' : IDL.Func(
+        [IDL.Opt(apikey_gen_args)],
+        [apikey_gen_result],
+        [],
+      ),
+    'generate_privkey' : IDL.Func(
+        [IDL.Opt(privkey_gen_args)],
+        [privkey_gen_result],
+        [],
+      ),
+    'http_request' : IDL.Func([http_request], [http_response], ['query']),
+    'http_request_update' : IDL.Func([http_request], [http_response], []),
+    'rotate_kek' : IDL.Func([], [rotate_kek_result], []),
+    'sign_digest_ic' : IDL.Func(
         [
-          IDL.Record({
-            'url' : IDL.Text,
-            'method' : IDL.Text,
-            'body' : IDL.Opt(IDL.Vec(IDL.Nat8)),
-            'headers' : IDL.Vec(http_header),
-          }),
+          IDL.Text,
+          IDL.Opt(HashAlgorithm),
+          IDL.Opt(SignatureFormat),
+          IDL.Opt(IDL.Vec(IDL.Vec(IDL.Nat8))),
         ],
-        [http_response],
+        [sign_result],
+        [],
+      ),
+    'sign_digest_mpc' : IDL.Func(
+        [IDL.Text, IDL.Text, IDL.Opt(HashAlgorithm), IDL.Opt(SignatureFormat)],
+        [sign_result],
         ['query'],
       ),
-    'sign_digest_ic' : IDL.Func([IDL.Text], [IDL.Text], []),
-    'sign_digest_mpc' : IDL.Func([IDL.Text, IDL.Text], [IDL.Text], ['query']),
   });
 };
-export const init = ({ IDL }) => { return []; };
\ No newline at end of file
+export const init = ({ IDL }) => {
+  const init_args = IDL.Record({
+    'raw_privkey_enabled' : IDL.Opt(IDL.Bool),
+    'ecdsa_key_name' : IDL.Opt(IDL.Text),
+  });
+  return [IDL.Opt(init_args)];
+};
\ No newline at end of file